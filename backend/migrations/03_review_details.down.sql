@@ -0,0 +1,4 @@
+ALTER TABLE user_tsumego_reviews DROP COLUMN moves;
+ALTER TABLE user_tsumego_reviews DROP COLUMN used_hint;
+ALTER TABLE user_tsumego_reviews DROP COLUMN num_wrong_moves;
+ALTER TABLE user_tsumego_reviews DROP COLUMN solve_time_ms;
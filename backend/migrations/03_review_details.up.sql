@@ -0,0 +1,4 @@
+ALTER TABLE user_tsumego_reviews ADD COLUMN solve_time_ms INTEGER;
+ALTER TABLE user_tsumego_reviews ADD COLUMN num_wrong_moves INTEGER;
+ALTER TABLE user_tsumego_reviews ADD COLUMN used_hint BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE user_tsumego_reviews ADD COLUMN moves VARCHAR;
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN is_suspended BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN suspension_reason VARCHAR;
@@ -0,0 +1,5 @@
+ALTER TABLE sessions ADD COLUMN created DATETIME;
+ALTER TABLE sessions ADD COLUMN last_seen DATETIME;
+ALTER TABLE sessions ADD COLUMN user_agent VARCHAR;
+ALTER TABLE sessions ADD COLUMN ip_address VARCHAR;
+CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (user_id);
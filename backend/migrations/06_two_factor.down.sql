@@ -0,0 +1,9 @@
+DROP INDEX IF EXISTS recovery_codes_by_user;
+DROP TABLE IF EXISTS user_recovery_codes;
+
+ALTER TABLE sessions DROP COLUMN two_factor_attempts;
+ALTER TABLE sessions DROP COLUMN two_factor_pending;
+
+ALTER TABLE users DROP COLUMN totp_last_step;
+ALTER TABLE users DROP COLUMN totp_enabled;
+ALTER TABLE users DROP COLUMN totp_secret;
@@ -0,0 +1,14 @@
+-- Two-factor authentication
+ALTER TABLE users ADD COLUMN totp_secret VARCHAR;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER;
+
+ALTER TABLE sessions ADD COLUMN two_factor_pending BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN two_factor_attempts INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS user_recovery_codes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    code_hash VARCHAR NOT NULL
+);
+CREATE INDEX IF NOT EXISTS recovery_codes_by_user ON user_recovery_codes (user_id);
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS api_tokens_by_user;
+DROP INDEX IF EXISTS api_tokens_hash_unique;
+DROP TABLE IF EXISTS api_tokens;
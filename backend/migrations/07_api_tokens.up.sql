@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    name VARCHAR NOT NULL,
+    token_hash VARCHAR NOT NULL,
+    scopes VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    last_used DATETIME
+);
+CREATE UNIQUE INDEX IF NOT EXISTS api_tokens_hash_unique ON api_tokens (token_hash);
+CREATE INDEX IF NOT EXISTS api_tokens_by_user ON api_tokens (user_id);
@@ -0,0 +1,6 @@
+DROP INDEX IF EXISTS oidc_logins_state_unique;
+DROP TABLE IF EXISTS oidc_logins;
+
+DROP INDEX IF EXISTS user_identities_by_user;
+DROP INDEX IF EXISTS user_identities_unique;
+DROP TABLE IF EXISTS user_identities;
@@ -0,0 +1,19 @@
+-- Logging in through an external OpenID Connect provider
+CREATE TABLE IF NOT EXISTS user_identities (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    issuer VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS user_identities_unique ON user_identities (issuer, subject);
+CREATE INDEX IF NOT EXISTS user_identities_by_user ON user_identities (user_id);
+
+CREATE TABLE IF NOT EXISTS oidc_logins (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    state VARCHAR NOT NULL,
+    code_verifier VARCHAR NOT NULL,
+    is_used BOOLEAN NOT NULL DEFAULT 0,
+    challenge_code VARCHAR,
+    expires DATETIME NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS oidc_logins_state_unique ON oidc_logins (state);
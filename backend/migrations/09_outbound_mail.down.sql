@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS outbound_mail_by_next_attempt;
+DROP TABLE IF EXISTS outbound_mail;
@@ -0,0 +1,14 @@
+-- Emails waiting to be sent, or which could not be sent. Sent emails are
+-- deleted, since they may contain login links.
+CREATE TABLE IF NOT EXISTS outbound_mail (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    recipient VARCHAR NOT NULL,
+    subject VARCHAR NOT NULL,
+    body VARCHAR NOT NULL,
+    created DATETIME NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL if the email failed too many times, and won't be retried
+    next_attempt DATETIME,
+    last_error VARCHAR
+);
+CREATE INDEX IF NOT EXISTS outbound_mail_by_next_attempt ON outbound_mail (next_attempt);
@@ -0,0 +1,2 @@
+-- Emails are sent with both a text and an HTML body
+ALTER TABLE outbound_mail ADD COLUMN html_body VARCHAR;
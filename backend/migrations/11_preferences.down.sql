@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS user_preferences_by_reminders;
+DROP TABLE IF EXISTS user_preferences;
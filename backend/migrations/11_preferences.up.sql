@@ -0,0 +1,13 @@
+-- Per-user preferences, including opt-in emails. Users without a row have
+-- the default preferences.
+CREATE TABLE IF NOT EXISTS user_preferences (
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users (id),
+    -- The user's offset from UTC, used to interpret local times
+    utc_offset_minutes INTEGER NOT NULL DEFAULT 0,
+    reminders_enabled BOOLEAN NOT NULL DEFAULT 0,
+    -- The local time of day to send reminders, in minutes after midnight
+    reminder_time INTEGER NOT NULL DEFAULT 1080,
+    -- The local date on which the most recent reminder was sent
+    last_reminder_date DATE
+);
+CREATE INDEX IF NOT EXISTS user_preferences_by_reminders ON user_preferences (reminders_enabled);
@@ -0,0 +1,2 @@
+ALTER TABLE user_preferences DROP COLUMN last_digest_date;
+ALTER TABLE user_preferences DROP COLUMN digest_enabled;
@@ -0,0 +1,4 @@
+-- Users can opt in to a weekly summary of their progress
+ALTER TABLE user_preferences ADD COLUMN digest_enabled BOOLEAN NOT NULL DEFAULT 0;
+-- The local date on which the most recent digest was sent
+ALTER TABLE user_preferences ADD COLUMN last_digest_date DATE;
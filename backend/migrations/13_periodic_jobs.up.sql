@@ -0,0 +1,10 @@
+-- The most recent run of each periodic job, so that jobs don't run again
+-- early after the server restarts
+CREATE TABLE IF NOT EXISTS periodic_jobs (
+    name VARCHAR PRIMARY KEY NOT NULL,
+    last_started DATETIME NOT NULL,
+    -- NULL if the most recent run hasn't finished
+    last_finished DATETIME,
+    -- NULL if the most recent run succeeded
+    last_error VARCHAR
+);
@@ -0,0 +1,2 @@
+ALTER TABLE periodic_jobs DROP COLUMN lease_expires;
+ALTER TABLE periodic_jobs DROP COLUMN lease_owner;
@@ -0,0 +1,5 @@
+-- A lease is held by the server process which is running a job, so that
+-- processes sharing the database don't run the same job at once. Expired
+-- leases may be taken over, in case the process holding it stopped.
+ALTER TABLE periodic_jobs ADD COLUMN lease_owner VARCHAR;
+ALTER TABLE periodic_jobs ADD COLUMN lease_expires DATETIME;
@@ -0,0 +1,9 @@
+-- Hints a user has been shown for a tsumego since their last review of it.
+-- The server records these itself, rather than trusting the client to say
+-- whether it used a hint, so that a hinted review is always capped at "Hard".
+CREATE TABLE IF NOT EXISTS hint_reveals (
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    tsumego_id INTEGER NOT NULL REFERENCES tsumego (id),
+    revealed DATETIME NOT NULL,
+    PRIMARY KEY (user_id, tsumego_id)
+);
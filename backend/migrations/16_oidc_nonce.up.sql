@@ -0,0 +1,2 @@
+-- The nonce sent to the provider, which must be echoed back in the ID token
+ALTER TABLE oidc_logins ADD COLUMN nonce VARCHAR NOT NULL DEFAULT '';
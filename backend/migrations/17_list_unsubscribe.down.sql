@@ -0,0 +1 @@
+ALTER TABLE outbound_mail DROP COLUMN list_unsubscribe;
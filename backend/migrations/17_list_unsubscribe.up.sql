@@ -0,0 +1,3 @@
+-- The one-click unsubscribe URL for the List-Unsubscribe header, if the email
+-- is from a mailing list
+ALTER TABLE outbound_mail ADD COLUMN list_unsubscribe VARCHAR;
@@ -0,0 +1,189 @@
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::SqliteConnection;
+
+use crate::{
+    model::{time, User},
+    result::Result,
+    state::State,
+};
+
+/// Tokens begin with this prefix, so that they are easy to recognise, e.g. by
+/// secret scanners.
+const TOKEN_PREFIX: &str = "tsu_";
+
+/// The number of random bytes in a token.
+const TOKEN_BYTES: usize = 24;
+
+/// The actions which a personal API token can be allowed to perform. A token
+/// can only be used for routes which need one of its scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+pub enum ApiScope {
+    /// Read the user's review counts and pending tsumego.
+    #[serde(rename = "readStats")]
+    ReadStats,
+    
+    /// Submit reviews, and ask for hints while solving.
+    #[serde(rename = "submitReviews")]
+    SubmitReviews,
+}
+
+/// A personal API token, which lets a script or another client act as the
+/// user without a session. Only a hash of the token itself is stored, so the
+/// token can only be shown to the user when it is created.
+#[derive(serde::Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    
+    /// A name chosen by the user, to help them recognise the token.
+    pub name: String,
+    
+    pub scopes: Vec<ApiScope>,
+    
+    pub created: time::DateTime,
+    
+    /// The last time this token was used, if ever.
+    #[serde(rename = "lastUsed")]
+    pub last_used: Option<time::DateTime>,
+}
+
+impl ApiToken {
+    /// Creates a new token for the user. Returns the token's details and the
+    /// token itself, which must be shown to the user now since it can't be
+    /// retrieved later.
+    pub async fn create(state: &State, user: &User, name: &str, scopes: Vec<ApiScope>) -> Result<(Self, String)> {
+        let now = time::now();
+        
+        let bytes: [u8; TOKEN_BYTES] = rand::thread_rng().gen();
+        let token = format!("{TOKEN_PREFIX}{}", to_hex(&bytes));
+        let token_hash = hash_token(&token);
+        let scopes_json = serde_json::to_string(&scopes)
+            .expect("Scopes should be serialisable");
+        
+        let id = sqlx::query_scalar!(
+            "INSERT INTO api_tokens (user_id, name, token_hash, scopes, created)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING id",
+            user.id,
+            name,
+            token_hash,
+            scopes_json,
+            now,
+        )
+            .fetch_one(&state.db)
+            .await?;
+        
+        log::info!("Created API token #{id} for user #{}", user.id);
+        
+        let api_token = Self {
+            id,
+            name: name.to_string(),
+            scopes,
+            created: now,
+            last_used: None,
+        };
+        Ok((api_token, token))
+    }
+    
+    /// Fetches all of the user's tokens, most recently created first.
+    pub async fn get_all_for_user(conn: &mut SqliteConnection, user_id: i64) -> Result<Vec<Self>> {
+        let tokens = sqlx::query!(
+            "SELECT id, name, scopes, created, last_used FROM api_tokens
+                WHERE user_id = ?
+                ORDER BY created DESC",
+            user_id,
+        )
+            .fetch_all(conn)
+            .await?
+            .into_iter()
+            .map(|t| Self {
+                id: t.id,
+                name: t.name,
+                scopes: parse_scopes(&t.scopes),
+                created: t.created,
+                last_used: t.last_used,
+            })
+            .collect();
+        
+        Ok(tokens)
+    }
+    
+    /// Revokes one of the user's tokens. Returns `false` if the user has no
+    /// token with that id.
+    pub async fn revoke(state: &State, user_id: i64, id: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+            id,
+            user_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    /// Finds the user who a token belongs to, and the token's scopes. Returns
+    /// `None` if the token doesn't exist, or the user can't currently log in,
+    /// e.g. because they are suspended.
+    pub async fn authenticate(state: &State, token: &str) -> Result<Option<(User, Vec<ApiScope>)>> {
+        let token_hash = hash_token(token);
+        
+        let record = sqlx::query!(
+            "SELECT api_tokens.id AS token_id, api_tokens.scopes,
+                    users.id, users.email, users.display_name, users.is_admin, users.require_password_change
+                FROM api_tokens INNER JOIN users ON api_tokens.user_id = users.id
+                WHERE api_tokens.token_hash = ?
+                AND users.is_suspended = 0
+                AND users.require_email_verification = 0",
+            token_hash,
+        )
+            .fetch_optional(&state.db)
+            .await?;
+        
+        let Some(record) = record else {
+            return Ok(None);
+        };
+        
+        let now = time::now();
+        sqlx::query!(
+            "UPDATE api_tokens SET last_used = ? WHERE id = ?",
+            now,
+            record.token_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        let user = User {
+            id: record.id,
+            email: record.email,
+            display_name: record.display_name,
+            is_admin: record.is_admin,
+            require_password_change: record.require_password_change,
+            two_factor_pending: false,
+        };
+        Ok(Some((user, parse_scopes(&record.scopes))))
+    }
+}
+
+/// Computes the hash of a token, which is stored in the database instead of
+/// the token itself. Tokens are random and long enough that a fast hash is
+/// sufficient; this also allows tokens to be looked up by their hash.
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Parses the scopes stored for a token. Unknown scopes are ignored, so that
+/// a scope can be removed without breaking existing tokens.
+fn parse_scopes(scopes_json: &str) -> Vec<ApiScope> {
+    serde_json::from_str::<Vec<serde_json::Value>>(scopes_json)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|scope| serde_json::from_value(scope).ok())
+        .collect()
+}
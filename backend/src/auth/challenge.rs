@@ -0,0 +1,51 @@
+use authlogic::{mail::Challenge, App, AppDb, Secret};
+
+use crate::{result::Result, state::State};
+
+/// The types of email challenge which are specific to this application, in
+/// addition to those built into `authlogic`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub enum CustomChallenge {
+    /// When the challenge is completed, the user's account and all of their
+    /// data are deleted.
+    DeleteAccount,
+    
+    /// When the challenge is completed, the user's email address is changed.
+    /// This challenge is sent to the new address, to verify that it belongs
+    /// to the user.
+    ChangeEmail {
+        #[serde(rename = "newEmail")]
+        new_email: String,
+    },
+    
+    /// Used to begin a session after the user logs in through an OpenID
+    /// Connect provider. This challenge isn't sent by email; its code is
+    /// stored with the login attempt, and completed straight away.
+    OidcLogin {
+        #[serde(rename = "loginId")]
+        login_id: i64,
+    },
+}
+
+/// Gets the type of challenge which a code is for, without completing it, so
+/// that the user can be asked to confirm before it is completed. Returns
+/// `None` if there is no such challenge, or it has expired.
+/// 
+/// Only the challenge id in the code is checked here; the secret part is
+/// checked by `authlogic::mail::complete_challenge`.
+pub async fn peek_challenge(state: &State, code: &Secret) -> Result<Option<Challenge<State>>> {
+    // Codes are the challenge id in hexadecimal, a dot, and the secret part
+    let Some(challenge_id) = code.expose()
+        .split_once('.')
+        .and_then(|(id, _)| i64::from_str_radix(id, 16).ok())
+    else {
+        return Ok(None);
+    };
+    
+    let challenge = state.get_challenge_by_id(challenge_id)
+        .await?
+        .filter(|data| data.expires > state.time_now())
+        .and_then(|data| serde_json::from_str(&data.challenge).ok());
+    
+    Ok(challenge)
+}
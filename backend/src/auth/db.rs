@@ -20,8 +20,10 @@ struct UserRecord {
     display_name: String,
     is_admin: bool,
     password_hash: PasswordHash,
+    is_suspended: bool,
     require_email_verification: bool,
     require_password_change: bool,
+    totp_enabled: bool,
 }
 
 impl From<UserRecord> for UserData<State> {
@@ -32,10 +34,13 @@ impl From<UserRecord> for UserData<State> {
                 email: u.email,
                 display_name: u.display_name,
                 is_admin: u.is_admin,
+                require_password_change: u.require_password_change,
+                // A new session for this user will need a second factor
+                two_factor_pending: u.totp_enabled,
             },
             password_hash: u.password_hash,
             state: UserState {
-                is_suspended: false,
+                is_suspended: u.is_suspended,
                 require_email_verification: u.require_email_verification,
                 require_password_change: u.require_password_change,
             },
@@ -43,13 +48,18 @@ impl From<UserRecord> for UserData<State> {
     }
 }
 
+// The `users.password_hash` column is `NOT NULL`, so a user without a
+// password is stored with an empty string instead. SQLite can't relax the
+// constraint without rebuilding the table, which would break the foreign keys
+// referring to it.
 impl AppDb for State {
     async fn get_user_data_by_id(&self, user_id: i64) -> Result<Option<UserData<State>>> {
         let user = sqlx::query_as!(
             UserRecord,
             r#"SELECT id, email, display_name, is_admin,
-                    password_hash "password_hash: PasswordHash",
-                    require_email_verification, require_password_change
+                    NULLIF(password_hash, '') "password_hash: String",
+                    is_suspended, require_email_verification, require_password_change,
+                    totp_enabled
                 FROM users
                 WHERE id = ?"#,
             user_id,
@@ -66,8 +76,9 @@ impl AppDb for State {
         let user = sqlx::query_as!(
             UserRecord,
             r#"SELECT id, email, display_name, is_admin,
-                    password_hash "password_hash: PasswordHash",
-                    require_email_verification, require_password_change
+                    NULLIF(password_hash, '') "password_hash: String",
+                    is_suspended, require_email_verification, require_password_change,
+                    totp_enabled
                 FROM users
                 WHERE email = ?"#,
             user_identifier,
@@ -82,7 +93,7 @@ impl AppDb for State {
         let id = sqlx::query_scalar!(
             "INSERT INTO users
                 (email, display_name, is_admin, password_hash, require_password_change, require_email_verification)
-                VALUES (?, ?, ?, ?, ?, ?)
+                VALUES (?, ?, ?, COALESCE(?, ''), ?, ?)
                 RETURNING id",
             data.user.email,
             data.user.display_name,
@@ -111,7 +122,7 @@ impl AppDb for State {
 
     async fn update_password(&self, user: &User, password_hash: PasswordHash, then_require_change: bool) -> Result<()> {
         sqlx::query!(
-            "UPDATE users SET password_hash = ?, require_password_change = ?
+            "UPDATE users SET password_hash = COALESCE(?, ''), require_password_change = ?
                 WHERE id = ?",
             password_hash,
             then_require_change,
@@ -124,19 +135,71 @@ impl AppDb for State {
     }
 
     async fn delete_user(&self, user_id: i64) -> Result<()> {
+        // Delete every row referring to this user, as well as the user
+        // themselves; otherwise the foreign key constraints would be broken.
+        let mut tx = self.db.begin().await?;
+        
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM challenges WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM user_recovery_codes WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM api_tokens WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM user_identities WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM user_preferences WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM hint_reveals WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM user_tsumego_reviews WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        sqlx::query!("DELETE FROM user_tsumego_stats WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        
+        // Queued emails would otherwise still be sent, and keep the user's
+        // name and address
+        sqlx::query!(
+            "DELETE FROM outbound_mail WHERE recipient = (SELECT email FROM users WHERE id = ?)",
+            user_id,
+        )
+            .execute(&mut *tx)
+            .await?;
+        
         sqlx::query!("DELETE FROM users WHERE id = ?", user_id)
-            .execute(&self.db)
+            .execute(&mut *tx)
             .await?;
         
+        tx.commit().await?;
+        
+        log::info!("Deleted user #{user_id} and all of their data");
         Ok(())
     }
     
     async fn get_session_by_id(&self, session_id: i64) -> Result<Option<SessionData<State>>> {
         let session = sqlx::query!(
             r#"SELECT users.id, users.email, users.display_name, users.is_admin,
-                    users.require_email_verification, users.require_password_change,
+                    users.is_suspended, users.require_email_verification, users.require_password_change,
                     sessions.token_hash "token_hash: Secret",
-                    sessions.expires
+                    sessions.expires, sessions.two_factor_pending
                 FROM sessions INNER JOIN users ON sessions.user_id = users.id
                 WHERE sessions.id = ?"#,
             session_id,
@@ -150,11 +213,16 @@ impl AppDb for State {
                 email: s.email,
                 display_name: s.display_name,
                 is_admin: s.is_admin,
+                require_password_change: s.require_password_change,
+                two_factor_pending: s.two_factor_pending,
             },
             user_state: UserState {
-                is_suspended: false,
+                is_suspended: s.is_suspended,
                 require_email_verification: s.require_email_verification,
-                require_password_change: s.require_password_change,
+                // A user who must change their password still needs a working
+                // session to do so; the requirement is enforced when a route
+                // asks for a `User`, instead of for every request.
+                require_password_change: false,
             },
             token_hash: s.token_hash,
             expires: s.expires,
@@ -162,14 +230,18 @@ impl AppDb for State {
     }
 
     async fn insert_session(&self, user: &User, token_hash: Secret, expires: time::DateTime) -> Result<i64> {
+        let now = time::now();
+        
         let id = sqlx::query_scalar!(
             "INSERT INTO sessions
-                (user_id, token_hash, expires)
-                VALUES (?, ?, ?)
+                (user_id, token_hash, expires, created, two_factor_pending)
+                SELECT ?, ?, ?, ?, totp_enabled FROM users WHERE id = ?
                 RETURNING id",
             user.id,
             token_hash,
             expires,
+            now,
+            user.id,
         )
             .fetch_one(&self.db)
             .await?;
@@ -201,6 +273,7 @@ impl AppDb for State {
     async fn get_challenge_by_id(&self, challenge_id: i64) -> Result<Option<mail::ChallengeData<State>>> {
         let record = sqlx::query!(
             r#"SELECT users.id AS user_id, users.email, users.display_name, users.is_admin,
+                    users.require_password_change, users.totp_enabled,
                     challenges.challenge,
                     challenges.code_hash "code_hash: Secret",
                     challenges.expires
@@ -217,6 +290,8 @@ impl AppDb for State {
                 email: r.email,
                 display_name: r.display_name,
                 is_admin: r.is_admin,
+                require_password_change: r.require_password_change,
+                two_factor_pending: r.totp_enabled,
             },
             challenge: r.challenge,
             code_hash: r.code_hash,
@@ -250,6 +325,49 @@ impl AppDb for State {
 }
 
 impl InnerState {
+    /// Suspends or unsuspends a user. When a user is suspended, all of their
+    /// sessions are revoked immediately.
+    pub async fn set_user_suspended(&self, user_id: i64, reason: Option<&str>) -> Result<()> {
+        let is_suspended = reason.is_some();
+        let mut tx = self.db.begin().await?;
+        
+        sqlx::query!(
+            "UPDATE users SET is_suspended = ?, suspension_reason = ?
+                WHERE id = ?",
+            is_suspended,
+            reason,
+            user_id,
+        )
+            .execute(&mut *tx)
+            .await?;
+        
+        if is_suspended {
+            sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        
+        tx.commit().await?;
+        
+        Ok(())
+    }
+    
+    /// Counts the challenges which have been issued to this user and have not
+    /// yet expired or been completed.
+    pub async fn count_pending_challenges(&self, user_id: i64) -> Result<i64> {
+        let now = time::now();
+        
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(1) FROM challenges WHERE user_id = ? AND expires > ?",
+            user_id,
+            now,
+        )
+            .fetch_one(&self.db)
+            .await?;
+        
+        Ok(count)
+    }
+    
     /// Deletes expired rows from the `sessions` table. This function will be
     /// called periodically.
     pub async fn delete_all_expired_sessions(&self) -> Result<()> {
@@ -275,4 +393,18 @@ impl InnerState {
         
         Ok(())
     }
+    
+    /// Deletes expired rows from the `oidc_logins` table, i.e. logins which
+    /// were started but never completed. This function will be called
+    /// periodically.
+    pub async fn delete_expired_oidc_logins(&self) -> Result<()> {
+        let now = time::now();
+        
+        log::info!("Deleting expired OpenID Connect logins");
+        sqlx::query!("DELETE FROM oidc_logins WHERE expires <= ?", now)
+            .execute(&self.db)
+            .await?;
+        
+        Ok(())
+    }
 }
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use string_template::Template;
+use minijinja::{context, Value};
 
 use authlogic::{
     mail::{Challenge, Notification},
@@ -8,6 +8,9 @@ use authlogic::{
 };
 
 use crate::{
+    auth::{unsubscribe_link, CustomChallenge, MailingList},
+    digest::WeeklyDigest,
+    mailer::Email,
     model::User,
     result::Result,
     state::State,
@@ -16,95 +19,236 @@ use crate::{
 impl AppMailer for State {
     async fn send_notification(&self, user: &User, notification: Notification) -> Result<()> {
         let mut args = HashMap::new();
-        let (template_path, subject) = match notification {
+        let (template_name, subject) = match notification {
             Notification::UserRegistered {ref temporary_password} => {
                 args.insert("temporary_password", temporary_password.expose());
-                ("templates/user_registered.txt", "Your account has been created")
+                ("user_registered", "Your account has been created")
             },
             Notification::PasswordChanged => {
-                ("templates/password_changed.txt", "Your password has been changed")
+                ("password_changed", "Your password has been changed")
             },
         };
         
-        compose_and_send(self, user, subject, template_path, args)
+        compose_and_send(self, user, &user.email, subject, template_name, args)
+            .await
     }
     
     async fn send_challenge(&self, user: &User, challenge: Challenge<State>, code: Secret) -> Result<()> {
-        let link = crate::routes::confirmation_link(self, code);
-        let mut args = HashMap::new();
-        args.insert("link", link.as_str());
-        
-        let (template_path, subject) = match challenge {
+        let (template_name, subject, to) = match &challenge {
             Challenge::LogIn => {
-                ("templates/log_in.txt", "Log in")
+                ("log_in", "Log in", &user.email)
             },
             Challenge::ResetPassword => {
-                ("templates/reset_password.txt", "Reset your password")
+                ("reset_password", "Reset your password", &user.email)
             },
             Challenge::VerifyNewUser => {
-                ("templates/verify_account.txt", "Verify your account")
+                ("verify_account", "Verify your account", &user.email)
+            },
+            Challenge::Custom(CustomChallenge::DeleteAccount) => {
+                ("delete_account", "Confirm account deletion", &user.email)
+            },
+            Challenge::Custom(CustomChallenge::ChangeEmail {new_email}) => {
+                ("change_email", "Confirm your new email address", new_email)
+            },
+            Challenge::Custom(CustomChallenge::OidcLogin {login_id}) => {
+                // This challenge is completed straight away, not sent by email
+                return crate::auth::oidc::store_challenge_code(self, *login_id, code)
+                    .await;
             },
-            Challenge::Custom(c) => c.never_happens(),
         };
         
-        compose_and_send(self, user, subject, template_path, args)
+        let link = crate::routes::confirmation_link(self, code);
+        let mut args = HashMap::new();
+        args.insert("link", link.as_str());
+        
+        compose_and_send(self, user, to, subject, template_name, args)
+            .await
     }
 }
 
-/// An error which might occur when trying to send an email.
-#[derive(Debug)]
-pub enum MailError {
-    Address(lettre::address::AddressError),
-    Compose(lettre::error::Error),
-    Smtp(lettre::transport::smtp::Error),
+/// Notifies a user that their account has been suspended by an admin.
+pub async fn send_suspended_notification(state: &State, user: &User, reason: &str) -> Result<()> {
+    let mut args = HashMap::new();
+    args.insert("reason", reason);
+    
+    compose_and_send(state, user, &user.email, "Your account has been suspended", "account_suspended", args)
+        .await
 }
 
-fn compose_and_send<'a>(state: &State, user: &'a User, subject: &str, template_path: &str, mut args: HashMap<&'static str, &'a str>) -> Result<()> {
-    args.insert("display_name", user.display_name.as_str());
-    let body = compose_email(template_path, args)?;
-    send_email(state, &user.email, subject, body)?;
-    Ok(())
+/// Notifies a user that their account is no longer suspended.
+pub async fn send_unsuspended_notification(state: &State, user: &User) -> Result<()> {
+    compose_and_send(state, user, &user.email, "Your account is no longer suspended", "account_unsuspended", HashMap::new())
+        .await
 }
 
-fn compose_email(template_path: &str, args: HashMap<&str, &str>) -> Result<String> {
-    let template_src = std::fs::read_to_string(template_path)?;
-    let template = Template::new(template_src.as_str());
-    Ok(template.render(&args))
+/// Notifies a user at their old email address that it has been changed, in
+/// case they didn't change it themselves.
+pub async fn send_email_changed_notification(state: &State, user: &User, old_email: &str) -> Result<()> {
+    let mut args = HashMap::new();
+    args.insert("new_email", user.email.as_str());
+    
+    compose_and_send(state, user, old_email, "Your email address has been changed", "email_changed", args)
+        .await
 }
 
-fn send_email(state: &State, to: &str, subject: &str, body: String) -> Result<(), MailError> {
-    // Only send mail in a release build; in a debug build, just log the
-    // message which would be sent.
-    if cfg!(debug_assertions) {
-        log::info!("Would send mail to {to}:\nSubject: {subject}\n{body}");
-    } else {
-        use lettre::{
-            message::header::ContentType,
-            transport::smtp::SmtpTransport,
-            Message,
-            Transport,
-        };
+/// Reminds a user that they have reviews due today. The email includes a link
+/// to unsubscribe from reminders.
+pub async fn send_daily_reminder(state: &State, user: &User, reviews_due: i64) -> Result<()> {
+    let args = context! {reviews_due => reviews_due.to_string()};
+    
+    compose_with_context(state, user, &user.email, "You have tsumego to review today", "daily_reminder", args, Some(MailingList::Reminders))
+        .await
+}
+
+/// Sends a user a summary of their progress over the past week. The email
+/// includes a link to unsubscribe from digests.
+pub async fn send_weekly_digest(state: &State, user: &User, digest: &WeeklyDigest) -> Result<()> {
+    let args = Value::from_serialize(digest);
+    
+    compose_with_context(state, user, &user.email, "Your week of tsumego practice", "weekly_digest", args, Some(MailingList::Digest))
+        .await
+}
+
+async fn compose_and_send(state: &State, user: &User, to: &str, subject: &str, template_name: &str, args: HashMap<&'static str, &str>) -> Result<()> {
+    compose_with_context(state, user, to, subject, template_name, Value::from_serialize(&args), None)
+        .await
+}
+
+/// Renders and sends an email, where the template arguments may be any
+/// values, not just strings. If the email is from a mailing list, the link to
+/// unsubscribe from it is passed to the template as `unsubscribe_link`, and
+/// also sent in the `List-Unsubscribe` header.
+async fn compose_with_context(state: &State, user: &User, to: &str, subject: &str, template_name: &str, args: Value, list: Option<MailingList>) -> Result<()> {
+    let unsubscribe_link = list.map(|list| unsubscribe_link(state, user.id, list));
+    let context = context! {
+        display_name => &user.display_name,
+        subject,
+        base_url => &state.cfg.base_url,
+        unsubscribe_link => &unsubscribe_link,
+        ..args
+    };
+    
+    let (body, html_body) = state.mail_templates.render(template_name, &context)?;
+    let email = Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body,
+        html_body: Some(html_body),
+        list_unsubscribe: unsubscribe_link,
+    };
+    crate::mail_queue::enqueue(state, email)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, middleware, test, App};
+    
+    use authlogic::AppDb;
+    
+    use crate::{
+        auth::CustomChallenge,
+        mailer::MemoryMailer,
+        state::{self, insert_test_user, State},
+    };
+    
+    #[actix_web::test]
+    async fn login_link_begins_session() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
         
-        let address_from = state.cfg.email_from
-            .parse()
-            .map_err(MailError::Address)?;
+        authlogic::mail::issue_login_challenge(&state, &user)
+            .await
+            .unwrap();
+        crate::mail_queue::send_due(&state)
+            .await
+            .unwrap();
         
-        let address_to = to
-            .parse()
-            .map_err(MailError::Address)?;
-
-        let email = Message::builder()
-            .from(address_from)
-            .to(address_to)
-            .subject(subject)
-            .header(ContentType::TEXT_PLAIN)
-            .body(body)
-            .map_err(MailError::Compose)?;
-        
-        SmtpTransport::unencrypted_localhost()
-            .send(&email)
-            .map_err(MailError::Smtp)?;
+        let sent = mailer.sent();
+        assert_eq!(1, sent.len());
+        assert_eq!("Log in", sent[0].subject);
+        
+        let link = mailer.last_link_to("alice@example.com")
+            .expect("Email should contain a link");
+        let path = link.strip_prefix(state.cfg.base_url.as_ref())
+            .expect("Link should be to this site");
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(authlogic::middleware::<State>))
+        ).await;
+        
+        let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/{path}")).to_request())
+            .await;
+        assert_eq!(StatusCode::TEMPORARY_REDIRECT, response.status());
+        assert!(response.response().cookies().any(|c| c.name() == state.cfg.session_token_cookie_name));
+        
+        // Each link can only be used once
+        let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/{path}")).to_request())
+            .await;
+        assert!(!response.status().is_success() && !response.status().is_redirection());
     }
     
-    Ok(())
+    #[actix_web::test]
+    async fn account_is_deleted_after_confirmation() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
+        
+        authlogic::mail::issue_custom_challenge(&state, &user, CustomChallenge::DeleteAccount)
+            .await
+            .unwrap();
+        crate::mail_queue::send_due(&state)
+            .await
+            .unwrap();
+        
+        let link = mailer.last_link_to("alice@example.com")
+            .expect("Email should contain a link");
+        let path = link.strip_prefix(state.cfg.base_url.as_ref())
+            .expect("Link should be to this site");
+        
+        // Another email to the user is still waiting to be sent
+        super::send_unsuspended_notification(&state, &user)
+            .await
+            .unwrap();
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(authlogic::middleware::<State>))
+                .wrap(middleware::from_fn(crate::middleware::clear_session_cookie_middleware))
+                .wrap(middleware::from_fn(crate::middleware::csrf_middleware))
+        ).await;
+        
+        // Following the link only asks the user to confirm
+        let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/{path}")).to_request())
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(state.get_user_data_by_id(user.id).await.unwrap().is_some());
+        
+        let request = test::TestRequest::post()
+            .uri(&format!("/{path}"))
+            .insert_header(("Referer", link.as_str()))
+            .to_request();
+        let response = test::call_service(&app, request)
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert!(state.get_user_data_by_id(user.id).await.unwrap().is_none());
+        
+        // The session begun by completing the challenge is removed
+        let cookies: Vec<_> = response.response().cookies()
+            .filter(|c| c.name() == state.cfg.session_token_cookie_name)
+            .collect();
+        assert_eq!(1, cookies.len());
+        assert_eq!("", cookies[0].value());
+        
+        let queued = crate::mail_queue::QueuedMail::get_all(&state)
+            .await
+            .unwrap();
+        assert!(queued.is_empty());
+    }
 }
@@ -1,10 +1,38 @@
+mod api_token;
+mod challenge;
 mod db;
 mod mail;
+mod oidc;
 mod state;
+mod totp;
+mod two_factor;
+mod unsubscribe;
 mod user;
 
-pub use mail::MailError;
-pub use user::register;
+pub use api_token::{ApiScope, ApiToken};
+pub use challenge::{peek_challenge, CustomChallenge};
+pub use mail::{send_daily_reminder, send_weekly_digest};
+pub use oidc::{begin_oidc_login, complete_oidc_login, OidcProvider};
+pub use two_factor::{
+    begin_two_factor_enrolment,
+    complete_two_factor_login,
+    disable_two_factor,
+    enable_two_factor,
+    require_two_factor_complete,
+};
+pub use user::{
+    change_password,
+    AccountUpdateError,
+    complete_email_change,
+    register,
+    request_email_change,
+    request_login_link,
+    request_password_reset,
+    suspend_user,
+    unsuspend_user,
+    update_profile,
+};
+pub use unsubscribe::{unsubscribe_link, verify_unsubscribe_token, MailingList};
 
 pub type MaybeAuth = authlogic::MaybeAuth<crate::state::State>;
 pub type Auth = authlogic::Auth<crate::state::State>;
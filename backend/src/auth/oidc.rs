@@ -0,0 +1,754 @@
+use std::time::Duration;
+
+use authlogic::{AppDb, PasswordHash, Secret, UserData, UserState};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::CustomChallenge,
+    config::Config,
+    model::{time, User},
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// The number of minutes the user has to log in with the provider, before
+/// the login attempt expires.
+const LOGIN_EXPIRE_AFTER_MINUTES: f64 = 10.0;
+
+/// The number of random bytes in the `state` and `nonce` parameters and the
+/// PKCE code verifier. RFC 7636 recommends 32 bytes for the code verifier.
+const RANDOM_BYTES: usize = 32;
+
+/// How long to wait for a connection to the provider, and for a whole request
+/// to it, so that an unresponsive provider can't hold up a login indefinitely.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// An external OpenID Connect provider which users can log in with, as an
+/// alternative to an email address and password.
+pub struct OidcProvider {
+    pub issuer: String,
+    pub authorization_endpoint: reqwest::Url,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub client_id: String,
+    /// Not needed if the provider registers this application as a public
+    /// client, since PKCE is used.
+    pub client_secret: Option<String>,
+    /// The client for requests to the provider, which is shared so that its
+    /// connections can be reused.
+    http: reqwest::Client,
+}
+
+/// The claims about a user which are returned by the provider's userinfo
+/// endpoint. Only the subject is guaranteed to be present.
+#[derive(serde::Deserialize)]
+pub struct UserInfo {
+    /// The provider's identifier for the user, which never changes.
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+/// The tokens returned by the provider's token endpoint.
+#[derive(serde::Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub id_token: String,
+}
+
+/// The claims in an ID token which show who issued it, who it was issued to,
+/// and for which login attempt.
+#[derive(serde::Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    /// The authorised party, which must be this application if the token has
+    /// more than one audience.
+    azp: Option<String>,
+    /// The expiry time, in seconds since the Unix epoch.
+    exp: i64,
+    nonce: Option<String>,
+}
+
+/// The `aud` claim may be a single string or an array of strings.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A login attempt which has been started, by redirecting the user to the
+/// provider.
+pub struct OidcLogin {
+    /// The URL of the provider's authorisation endpoint, which the user should
+    /// be redirected to.
+    pub redirect_url: String,
+    /// This must also be stored in a cookie, so that the login can only be
+    /// completed in the same browser which started it.
+    pub state: String,
+}
+
+impl OidcProvider {
+    /// Gets the provider from the config, if one is configured.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        let authorization_endpoint = cfg.oidc_authorization_endpoint.as_ref()?;
+        let authorization_endpoint = reqwest::Url::parse(authorization_endpoint)
+            .unwrap_or_else(|err| {
+                eprintln!("Invalid OIDC_AUTHORIZATION_ENDPOINT in config: {err:?}");
+                std::process::exit(1);
+            });
+        let http = http_client()
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to create HTTP client for OIDC: {err:?}");
+                std::process::exit(1);
+            });
+        
+        Some(Self {
+            issuer: cfg.oidc_issuer.as_ref()?.to_string(),
+            authorization_endpoint,
+            token_endpoint: cfg.oidc_token_endpoint.as_ref()?.to_string(),
+            userinfo_endpoint: cfg.oidc_userinfo_endpoint.as_ref()?.to_string(),
+            client_id: cfg.oidc_client_id.as_ref()?.to_string(),
+            client_secret: cfg.oidc_client_secret.as_ref().map(|s| s.to_string()),
+            http,
+        })
+    }
+    
+    /// Builds the URL which the user is redirected to in order to log in with
+    /// the provider.
+    pub fn authorization_url(&self, redirect_uri: &str, state: &str, nonce: &str, code_challenge: &str) -> String {
+        let mut url = self.authorization_endpoint.clone();
+        
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", "openid email profile")
+            .append_pair("state", state)
+            .append_pair("nonce", nonce)
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        
+        url.into()
+    }
+    
+    /// Exchanges an authorisation code for an access token and ID token, at
+    /// the provider's token endpoint.
+    pub async fn exchange_code(&self, redirect_uri: &str, code: &str, code_verifier: &str) -> Result<TokenResponse> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &self.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        
+        let response = self.http
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        
+        Ok(response)
+    }
+    
+    /// Checks the claims in an ID token from the provider's token endpoint,
+    /// and returns the subject, i.e. the provider's identifier for the user.
+    /// The token must be issued by this provider, to this application, for
+    /// the login attempt with the given nonce, and must not have expired.
+    /// 
+    /// The token's signature isn't checked. The token is received directly
+    /// from the token endpoint, so the TLS connection authenticates it
+    /// instead, as allowed by OpenID Connect Core 1.0, section 3.1.3.7.
+    pub fn validate_id_token(&self, id_token: &str, nonce: &str, now: time::DateTime) -> Result<String> {
+        let claims: IdTokenClaims = id_token.split('.')
+            .nth(1)
+            .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .or_401_unauthorised()?;
+        
+        let audiences = match claims.aud {
+            Audience::One(aud) => vec![aud],
+            Audience::Many(auds) => auds,
+        };
+        let is_for_this_client = audiences.contains(&self.client_id)
+            && (audiences.len() == 1 || claims.azp.as_ref() == Some(&self.client_id));
+        
+        let is_valid = claims.iss == self.issuer
+            && is_for_this_client
+            && claims.exp > now.and_utc().timestamp()
+            && claims.nonce.as_deref() == Some(nonce);
+        
+        if !is_valid {
+            log::warn!("Rejected an ID token from {} with invalid claims", self.issuer);
+            return Err(AppError::UNAUTHORIZED);
+        }
+        
+        Ok(claims.sub)
+    }
+    
+    /// Fetches the claims about the user who the access token was issued to.
+    pub async fn get_userinfo(&self, access_token: &str) -> Result<UserInfo> {
+        let info = self.http
+            .get(&self.userinfo_endpoint)
+            .bearer_auth(access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        
+        Ok(info)
+    }
+}
+
+/// Builds the client for requests to the provider.
+fn http_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+}
+
+/// The URL which the provider redirects users back to. This must match the
+/// `oidc_callback` route URL, and must be registered with the provider.
+pub fn redirect_uri(state: &State) -> String {
+    format!("{}oidc/callback", state.cfg.base_url)
+}
+
+/// Starts logging in with the provider. The code verifier for PKCE is stored
+/// in the database, to be used when the provider redirects the user back.
+pub async fn begin_oidc_login(state: &State, provider: &OidcProvider) -> Result<OidcLogin> {
+    let login_state = random_string();
+    let nonce = random_string();
+    let code_verifier = random_string();
+    let expires = time::add_days(time::now(), LOGIN_EXPIRE_AFTER_MINUTES / (24.0 * 60.0));
+    
+    sqlx::query!(
+        "INSERT INTO oidc_logins (state, nonce, code_verifier, expires) VALUES (?, ?, ?, ?)",
+        login_state,
+        nonce,
+        code_verifier,
+        expires,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    let redirect_url = provider.authorization_url(&redirect_uri(state), &login_state, &nonce, &pkce_challenge(&code_verifier));
+    Ok(OidcLogin {
+        redirect_url,
+        state: login_state,
+    })
+}
+
+/// Completes logging in with the provider, after it redirects the user back
+/// with an authorisation code. Returns a challenge code which must be passed
+/// to `authlogic::mail::complete_challenge` to begin a session for the user.
+/// 
+/// The user is found by their identity with the provider, or otherwise by
+/// their email address, if the provider has verified it. A new user is
+/// registered if there is no user with that address.
+pub async fn complete_oidc_login(state: &State, provider: &OidcProvider, login_state: &str, code: &str) -> Result<Secret> {
+    // Each login attempt can only be completed once
+    let now = time::now();
+    let login = sqlx::query!(
+        "UPDATE oidc_logins SET is_used = 1
+            WHERE state = ? AND is_used = 0 AND expires > ?
+            RETURNING id, nonce, code_verifier",
+        login_state,
+        now,
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .or_401_unauthorised()?;
+    
+    let tokens = provider.exchange_code(&redirect_uri(state), code, &login.code_verifier)
+        .await?;
+    let subject = provider.validate_id_token(&tokens.id_token, &login.nonce, now)?;
+    let info = provider.get_userinfo(&tokens.access_token)
+        .await?;
+    
+    // The userinfo response must be about the user the ID token was issued
+    // for, or it could have been substituted
+    if info.sub != subject {
+        return Err(AppError::UNAUTHORIZED);
+    }
+    
+    let user = find_or_register_user(state, provider, info)
+        .await?;
+    
+    // `authlogic` only begins sessions after a password login or a completed
+    // challenge. The challenge for this login isn't sent by email; instead,
+    // `store_challenge_code` puts the code in the database for us to take.
+    let challenge = CustomChallenge::OidcLogin {login_id: login.id};
+    authlogic::mail::issue_custom_challenge(state, &user, challenge)
+        .await?;
+    
+    let challenge_code = sqlx::query_scalar!(
+        "DELETE FROM oidc_logins WHERE id = ? RETURNING challenge_code",
+        login.id,
+    )
+        .fetch_one(&state.db)
+        .await?
+        .or_401_unauthorised()?;
+    
+    Ok(Secret::from(challenge_code))
+}
+
+/// Stores the code for a challenge issued by `complete_oidc_login`. This is
+/// called instead of sending the challenge by email.
+pub async fn store_challenge_code(state: &State, login_id: i64, code: Secret) -> Result<()> {
+    let code = code.expose();
+    sqlx::query!(
+        "UPDATE oidc_logins SET challenge_code = ? WHERE id = ?",
+        code,
+        login_id,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    Ok(())
+}
+
+async fn find_or_register_user(state: &State, provider: &OidcProvider, info: UserInfo) -> Result<User> {
+    let user_id = sqlx::query_scalar!(
+        "SELECT user_id FROM user_identities WHERE issuer = ? AND subject = ?",
+        provider.issuer,
+        info.sub,
+    )
+        .fetch_optional(&state.db)
+        .await?;
+    
+    let user_data = match user_id {
+        Some(user_id) => state.get_user_data_by_id(user_id)
+            .await?
+            .or_401_unauthorised()?,
+        None => {
+            // Only link by email address if the provider vouches for it;
+            // otherwise anyone could take over an account by claiming its
+            // address with the provider.
+            let email = info.email
+                .filter(|_| info.email_verified)
+                .or_401_unauthorised()?;
+            
+            let user_data = match state.get_user_data_by_identifier(&email).await? {
+                Some(user_data) => {
+                    if user_data.state.require_email_verification {
+                        // Someone registered this address without verifying
+                        // it, so the password they chose can't be trusted
+                        state.update_password(&user_data.user, PasswordHash::NONE, false)
+                            .await?;
+                        state.verify_user(&user_data.user)
+                            .await?;
+                    }
+                    user_data
+                },
+                None => register_user(state, email, info.name).await?,
+            };
+            
+            sqlx::query!(
+                "INSERT INTO user_identities (user_id, issuer, subject) VALUES (?, ?, ?)",
+                user_data.user.id,
+                provider.issuer,
+                info.sub,
+            )
+                .execute(&state.db)
+                .await?;
+            
+            log::info!("Linked user #{} to an identity from {}", user_data.user.id, provider.issuer);
+            user_data
+        },
+    };
+    
+    if user_data.state.is_suspended {
+        return Err(authlogic::Error::UserIsSuspended.into());
+    }
+    
+    Ok(user_data.user)
+}
+
+/// Registers a new user without a password. The user's email address is
+/// already verified by the provider.
+async fn register_user(state: &State, email: String, name: Option<String>) -> Result<UserData<State>> {
+    let display_name = name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+    
+    let mut user_data: UserData<State> = UserData {
+        user: User {
+            id: -1,
+            email,
+            display_name,
+            is_admin: false,
+            require_password_change: false,
+            two_factor_pending: false,
+        },
+        password_hash: PasswordHash::NONE,
+        state: UserState {
+            is_suspended: false,
+            require_email_verification: false,
+            require_password_change: false,
+        },
+    };
+    
+    user_data.user.id = state.insert_user(&user_data)
+        .await?;
+    
+    log::info!("Registered user #{} from an OpenID Connect login", user_data.user.id);
+    Ok(user_data)
+}
+
+/// Computes the PKCE code challenge for a code verifier, using the `S256`
+/// method from RFC 7636.
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn random_string() -> String {
+    let bytes: [u8; RANDOM_BYTES] = rand::thread_rng().gen();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+    
+    use actix_web::{
+        cookie::Cookie,
+        post,
+        get,
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web::{Data, Form, Query},
+        App,
+        HttpRequest,
+        HttpResponse,
+        HttpServer,
+    };
+    use authlogic::AppDb;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use serde_json::{json, Value};
+    
+    use crate::{
+        mailer::MemoryMailer,
+        model::time,
+        result::Result,
+        state::{self, State},
+    };
+    use super::{begin_oidc_login, complete_oidc_login, http_client, pkce_challenge, OidcProvider};
+    
+    /// The example from RFC 7636, appendix B.
+    const CODE_VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CODE_CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+    
+    const AUTH_CODE: &str = "test-auth-code";
+    const ACCESS_TOKEN: &str = "test-access-token";
+    const NONCE: &str = "test-nonce";
+    const CLIENT_ID: &str = "tsumego";
+    
+    /// The state of a mock provider, shared between its endpoints.
+    struct MockProvider {
+        issuer: String,
+        /// The claims returned by the userinfo endpoint.
+        userinfo: Value,
+        /// The code challenge and nonce from the last authorisation request.
+        authorization: Mutex<(String, String)>,
+    }
+    
+    #[derive(serde::Deserialize)]
+    struct AuthorizeQuery {
+        code_challenge: String,
+        nonce: String,
+    }
+    
+    #[derive(serde::Deserialize)]
+    struct TokenRequest {
+        grant_type: String,
+        code: String,
+        code_verifier: String,
+    }
+    
+    /// Encodes an ID token with the given claims. The mock provider's tokens
+    /// aren't signed, since signatures aren't checked.
+    fn id_token(claims: &Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "RS256"}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        format!("{header}.{payload}.signature")
+    }
+    
+    /// A mock authorisation endpoint, which records the code challenge and
+    /// nonce, as if the user logged in.
+    #[get("/authorize")]
+    async fn mock_authorize(mock: Data<MockProvider>, query: Query<AuthorizeQuery>) -> HttpResponse {
+        *mock.authorization.lock().unwrap() = (query.code_challenge.clone(), query.nonce.clone());
+        HttpResponse::Ok().finish()
+    }
+    
+    /// A mock token endpoint, which checks the code verifier against the code
+    /// challenge the mock provider was given.
+    #[post("/token")]
+    async fn mock_token(mock: Data<MockProvider>, form: Form<TokenRequest>) -> HttpResponse {
+        let (challenge, nonce) = mock.authorization.lock().unwrap().clone();
+        if form.grant_type != "authorization_code" || form.code != AUTH_CODE || pkce_challenge(&form.code_verifier) != challenge {
+            return HttpResponse::BadRequest().json(json!({"error": "invalid_grant"}));
+        }
+        
+        let claims = json!({
+            "iss": mock.issuer,
+            "sub": mock.userinfo["sub"],
+            "aud": CLIENT_ID,
+            "exp": time::now().and_utc().timestamp() + 300,
+            "nonce": nonce,
+        });
+        HttpResponse::Ok().json(json!({
+            "access_token": ACCESS_TOKEN,
+            "id_token": id_token(&claims),
+            "token_type": "Bearer",
+        }))
+    }
+    
+    #[get("/userinfo")]
+    async fn mock_userinfo(mock: Data<MockProvider>, request: HttpRequest) -> HttpResponse {
+        let expected = format!("Bearer {ACCESS_TOKEN}");
+        if request.headers().get("Authorization").is_none_or(|h| h != expected.as_str()) {
+            return HttpResponse::Unauthorized().finish();
+        }
+        HttpResponse::Ok().json(&mock.userinfo)
+    }
+    
+    /// Starts a mock OIDC provider on a random local port, which returns the
+    /// given claims from its userinfo endpoint.
+    fn start_mock_provider(userinfo: Value) -> OidcProvider {
+        let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        
+        let mock = Data::new(MockProvider {
+            issuer: base.clone(),
+            userinfo,
+            authorization: Mutex::new((CODE_CHALLENGE.to_string(), NONCE.to_string())),
+        });
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(mock.clone())
+                .service(mock_authorize)
+                .service(mock_token)
+                .service(mock_userinfo)
+        })
+            .workers(1)
+            .listen(listener)
+            .unwrap();
+        actix_web::rt::spawn(server.run());
+        
+        OidcProvider {
+            issuer: base.clone(),
+            authorization_endpoint: reqwest::Url::parse(&format!("{base}/authorize")).unwrap(),
+            token_endpoint: format!("{base}/token"),
+            userinfo_endpoint: format!("{base}/userinfo"),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            http: http_client().unwrap(),
+        }
+    }
+    
+    fn alice(email_verified: bool) -> Value {
+        json!({
+            "sub": "user-123",
+            "email": "alice@example.com",
+            "email_verified": email_verified,
+            "name": "Alice",
+        })
+    }
+    
+    fn example_provider() -> OidcProvider {
+        OidcProvider {
+            issuer: "https://id.example.com".to_string(),
+            authorization_endpoint: reqwest::Url::parse("https://id.example.com/authorize").unwrap(),
+            token_endpoint: "https://id.example.com/token".to_string(),
+            userinfo_endpoint: "https://id.example.com/userinfo".to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            http: http_client().unwrap(),
+        }
+    }
+    
+    /// Logs in with the mock provider, as far as `complete_oidc_login`.
+    /// Returns the id of the user the identity is linked to.
+    async fn log_in(state: &State, provider: &OidcProvider) -> Result<i64> {
+        let login = begin_oidc_login(state, provider)
+            .await?;
+        reqwest::get(&login.redirect_url)
+            .await?
+            .error_for_status()?;
+        complete_oidc_login(state, provider, &login.state, AUTH_CODE)
+            .await?;
+        
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM user_identities WHERE issuer = ? AND subject = 'user-123'",
+            provider.issuer,
+        )
+            .fetch_one(&state.db)
+            .await?;
+        Ok(user_id)
+    }
+    
+    async fn count_identities(state: &State) -> i64 {
+        sqlx::query_scalar!("SELECT COUNT(*) FROM user_identities")
+            .fetch_one(&state.db)
+            .await
+            .unwrap()
+    }
+    
+    #[test]
+    fn rfc_7636_code_challenge() {
+        assert_eq!(CODE_CHALLENGE, pkce_challenge(CODE_VERIFIER));
+    }
+    
+    #[test]
+    fn authorization_url_has_pkce_parameters() {
+        let provider = example_provider();
+        
+        let url = provider.authorization_url("http://127.0.0.1:8000/oidc/callback", "xyz", NONCE, CODE_CHALLENGE);
+        assert!(url.starts_with("https://id.example.com/authorize?response_type=code&client_id=tsumego&"));
+        assert!(url.contains("redirect_uri=http%3A%2F%2F127.0.0.1%3A8000%2Foidc%2Fcallback"));
+        assert!(url.contains(&format!("nonce={NONCE}&")));
+        assert!(url.contains(&format!("code_challenge={CODE_CHALLENGE}&code_challenge_method=S256")));
+    }
+    
+    #[test]
+    fn id_token_claims_are_checked() {
+        let provider = example_provider();
+        let now = time::now();
+        let exp = now.and_utc().timestamp() + 300;
+        let valid = json!({
+            "iss": provider.issuer,
+            "sub": "user-123",
+            "aud": CLIENT_ID,
+            "exp": exp,
+            "nonce": NONCE,
+        });
+        
+        let subject = provider.validate_id_token(&id_token(&valid), NONCE, now)
+            .unwrap();
+        assert_eq!("user-123", subject);
+        
+        let invalid = [
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!("another-client")),
+            ("aud", json!([CLIENT_ID, "another-client"])),
+            ("exp", json!(exp - 600)),
+            ("nonce", json!("another-nonce")),
+            ("nonce", Value::Null),
+        ];
+        for (claim, value) in invalid {
+            let mut claims = valid.clone();
+            claims[claim] = value;
+            assert!(provider.validate_id_token(&id_token(&claims), NONCE, now).is_err(), "{claim} should be checked");
+        }
+        
+        assert!(provider.validate_id_token("not a token", NONCE, now).is_err());
+    }
+    
+    #[actix_web::test]
+    async fn exchange_code_with_mock_provider() {
+        let provider = start_mock_provider(alice(true));
+        
+        let tokens = provider.exchange_code("http://localhost/oidc/callback", AUTH_CODE, CODE_VERIFIER)
+            .await
+            .unwrap();
+        let subject = provider.validate_id_token(&tokens.id_token, NONCE, time::now())
+            .unwrap();
+        let info = provider.get_userinfo(&tokens.access_token)
+            .await
+            .unwrap();
+        
+        assert_eq!("user-123", subject);
+        assert_eq!("user-123", info.sub);
+        assert_eq!(Some("alice@example.com"), info.email.as_deref());
+        assert!(info.email_verified);
+    }
+    
+    #[actix_web::test]
+    async fn mock_provider_rejects_wrong_verifier() {
+        let provider = start_mock_provider(alice(true));
+        
+        let result = provider.exchange_code("http://localhost/oidc/callback", AUTH_CODE, "wrong-verifier")
+            .await;
+        assert!(result.is_err());
+    }
+    
+    #[actix_web::test]
+    async fn new_user_is_registered() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let provider = start_mock_provider(alice(true));
+        
+        let user_id = log_in(&state, &provider).await.unwrap();
+        let user_data = state.get_user_data_by_identifier("alice@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user_id, user_data.user.id);
+        assert_eq!("Alice", user_data.user.display_name);
+        assert!(!user_data.state.require_email_verification);
+        
+        // Logging in again finds the same user by their identity
+        assert_eq!(user_id, log_in(&state, &provider).await.unwrap());
+        assert_eq!(1, count_identities(&state).await);
+    }
+    
+    #[actix_web::test]
+    async fn existing_user_is_linked_by_verified_email() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let user = state::insert_test_user(&state, "alice@example.com").await;
+        let provider = start_mock_provider(alice(true));
+        
+        assert_eq!(user.id, log_in(&state, &provider).await.unwrap());
+    }
+    
+    #[actix_web::test]
+    async fn unverified_email_is_not_linked() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        state::insert_test_user(&state, "alice@example.com").await;
+        let provider = start_mock_provider(alice(false));
+        
+        assert!(log_in(&state, &provider).await.is_err());
+        assert_eq!(0, count_identities(&state).await);
+    }
+    
+    #[actix_web::test]
+    async fn cancelled_login_returns_to_login_page() {
+        let state = state::for_test_with_oidc(Box::new(MemoryMailer::default()), Some(example_provider())).await;
+        let app = init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+        ).await;
+        
+        // The provider gives an error instead of a code
+        let request = TestRequest::get()
+            .uri("/oidc/callback?error=access_denied&state=example")
+            .cookie(Cookie::new("oidc_state", "example"))
+            .to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(StatusCode::FOUND, response.status());
+        assert_eq!(Some("/"), response.headers().get("Location").and_then(|l| l.to_str().ok()));
+        
+        let cookie = response.response()
+            .cookies()
+            .find(|c| c.name() == "oidc_state")
+            .expect("Response should remove the state cookie");
+        assert_eq!("", cookie.value());
+    }
+}
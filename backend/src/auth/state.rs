@@ -8,7 +8,7 @@ impl authlogic::AppTypes for State {
     type DateTime = time::DateTime;
     type ID = i64;
     type User = User;
-    type CustomChallenge = authlogic::mail::NoCustomChallenges;
+    type CustomChallenge = crate::auth::CustomChallenge;
     type Error = AppError;
 }
 
@@ -28,6 +28,11 @@ impl authlogic::AppConfig for State {
 
 impl authlogic::App for State {
     fn time_now(&self) -> time::DateTime {
+        #[cfg(test)]
+        if let Some(now) = *self.fixed_time.lock().unwrap() {
+            return now;
+        }
+        
         time::now()
     }
 }
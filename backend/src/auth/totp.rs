@@ -0,0 +1,220 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::model::time;
+
+/// The length of each time step, in seconds. This is the default from RFC
+/// 6238, and the only value which most authenticator apps support.
+const STEP_SECONDS: i64 = 30;
+
+/// The number of digits in each code.
+const DIGITS: u32 = 6;
+
+/// The number of time steps either side of the current one for which a code
+/// is still accepted, to allow for the user's device clock being slightly
+/// wrong, or the user being slow to type the code.
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+/// The number of bytes in a new secret. RFC 4226 recommends 160 bits.
+const SECRET_BYTES: usize = 20;
+
+/// The number of recovery codes issued when two-factor authentication is
+/// enabled.
+const NUM_RECOVERY_CODES: usize = 10;
+
+/// The number of characters in each recovery code, not including the hyphen.
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// The alphabet for base32 from RFC 4648, which authenticator apps expect
+/// secrets to be written in.
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new random secret, encoded in base32.
+pub fn generate_secret() -> String {
+    let bytes: [u8; SECRET_BYTES] = rand::thread_rng().gen();
+    base32_encode(&bytes)
+}
+
+/// Builds a `otpauth://` URI for the secret, which authenticator apps can
+/// import, usually by scanning it as a QR code.
+/// 
+/// See https://github.com/google/google-authenticator/wiki/Key-Uri-Format
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = percent_encode(issuer);
+    let account = percent_encode(account);
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}")
+}
+
+/// Checks a code against a secret, at the given time. If the code is correct,
+/// returns the time step it belongs to; this should be recorded, so that the
+/// code can't be used again. Returns `None` if the code is incorrect.
+pub fn verify_code(secret: &str, code: &str, now: time::DateTime) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = base32_decode(secret)?;
+    
+    let current_step = now.and_utc().timestamp().div_euclid(STEP_SECONDS);
+    (current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS)
+        .find(|&step| code_at_step(&key, step) == code)
+}
+
+/// Generates the code for a secret at the given time, as an authenticator app
+/// would.
+#[cfg(test)]
+pub fn generate_code(secret: &str, now: time::DateTime) -> String {
+    let key = base32_decode(secret).expect("Secret should be valid base32");
+    let step = now.and_utc().timestamp().div_euclid(STEP_SECONDS);
+    format!("{:0width$}", code_at_step(&key, step), width = DIGITS as usize)
+}
+
+/// Generates a new set of single-use recovery codes, which the user can use
+/// instead of a code from their authenticator app, e.g. if they lose their
+/// device.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    
+    (0..NUM_RECOVERY_CODES)
+        .map(|_| {
+            let code: String = (0..RECOVERY_CODE_LENGTH)
+                .map(|_| BASE32_ALPHABET[rng.gen_range(0..BASE32_ALPHABET.len())].to_ascii_lowercase() as char)
+                .collect();
+            let (left, right) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            format!("{left}-{right}")
+        })
+        .collect()
+}
+
+/// Computes the hash of a recovery code, which is stored in the database
+/// instead of the code itself. Recovery codes are random and long enough that
+/// a fast hash is sufficient. The code is normalised first, so that it can be
+/// entered with or without the hyphen, and in either case.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalised: String = code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    
+    Sha256::digest(normalised.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Computes the code for a time step, as in RFC 6238.
+fn code_at_step(key: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key)
+        .expect("HMAC should accept keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    
+    // Dynamic truncation, as in RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    (value & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// Encodes bytes in base32, without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut num_bits = 0;
+    
+    for &b in bytes {
+        buffer = (buffer << 8) | b as u32;
+        num_bits += 8;
+        while num_bits >= 5 {
+            num_bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> num_bits) & 0x1f) as usize] as char);
+        }
+    }
+    if num_bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - num_bits)) & 0x1f) as usize] as char);
+    }
+    encoded
+}
+
+/// Decodes base32, ignoring case and any padding. Returns `None` if the input
+/// contains other characters.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut num_bits = 0;
+    
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE32_ALPHABET.iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        num_bits += 5;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            decoded.push((buffer >> num_bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Percent-encodes a string for use in a URI, leaving only unreserved
+/// characters unencoded.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base32_decode, base32_encode, code_at_step, hash_recovery_code, verify_code};
+    use crate::model::time;
+    
+    /// The secret used for the test vectors in RFC 6238, appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+    
+    fn at(timestamp: i64) -> time::DateTime {
+        chrono::DateTime::from_timestamp(timestamp, 0)
+            .unwrap()
+            .naive_utc()
+    }
+    
+    #[test]
+    fn base32_round_trip() {
+        let encoded = base32_encode(RFC_SECRET);
+        assert_eq!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", encoded);
+        assert_eq!(RFC_SECRET, base32_decode(&encoded.to_lowercase()).unwrap());
+    }
+    
+    #[test]
+    fn rfc_6238_test_vectors() {
+        // The RFC gives 8-digit codes; these are their last 6 digits
+        assert_eq!(287082, code_at_step(RFC_SECRET, 59 / 30));
+        assert_eq!(81804, code_at_step(RFC_SECRET, 1111111109 / 30));
+        assert_eq!(5924, code_at_step(RFC_SECRET, 1234567890 / 30));
+    }
+    
+    #[test]
+    fn verify_with_fixed_clock() {
+        let secret = base32_encode(RFC_SECRET);
+        let step = 1234567890 / 30;
+        
+        assert_eq!(Some(step), verify_code(&secret, "005924", at(1234567890)));
+        // Codes from adjacent time steps are accepted, but no further
+        assert_eq!(Some(step), verify_code(&secret, "005924", at(1234567890 + 30)));
+        assert_eq!(None, verify_code(&secret, "005924", at(1234567890 + 60)));
+        // Codes must have exactly six digits
+        assert_eq!(None, verify_code(&secret, "5924", at(1234567890)));
+        assert_eq!(None, verify_code(&secret, "+05924", at(1234567890)));
+    }
+    
+    #[test]
+    fn recovery_codes_are_normalised() {
+        assert_eq!(hash_recovery_code("abcde-fghij"), hash_recovery_code(" ABCDEFGHIJ "));
+        assert_ne!(hash_recovery_code("abcde-fghij"), hash_recovery_code("abcde-fghik"));
+    }
+}
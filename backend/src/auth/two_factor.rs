@@ -0,0 +1,397 @@
+use actix_web::HttpRequest;
+use authlogic::App;
+
+use crate::{
+    auth::{totp, Auth},
+    model::User,
+    rate_limit,
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// The name of this application, as shown in authenticator apps.
+const ISSUER: &str = "Tsumego Practice";
+
+/// The number of incorrect codes which can be given for one session, before
+/// the session is revoked and the user must log in again.
+const MAX_ATTEMPTS_PER_SESSION: i64 = 5;
+
+/// A new TOTP secret, which the user should add to their authenticator app
+/// before enabling two-factor authentication.
+#[derive(serde::Serialize)]
+pub struct TwoFactorEnrolment {
+    pub secret: String,
+    pub uri: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct TwoFactorOutcome {
+    /// The user's new recovery codes, if two-factor authentication was just
+    /// enabled. These are not stored, so can only be shown to the user once.
+    #[serde(rename = "recoveryCodes")]
+    pub recovery_codes: Option<Vec<String>>,
+    pub error: Option<TwoFactorError>,
+}
+
+/// Represents an error preventing two-factor authentication from being
+/// enabled or disabled, which should be resolved by the user entering a
+/// different code.
+#[derive(serde::Serialize)]
+pub enum TwoFactorError {
+    #[serde(rename = "Incorrect code")]
+    IncorrectCode,
+}
+
+/// Returns an error if the session still needs a second factor before the
+/// user is fully logged in. Routes which ask for `Auth` instead of `User`
+/// must call this, unless they should be usable by a half-logged-in user.
+pub fn require_two_factor_complete(auth: &Auth) -> Result<()> {
+    if auth.user.two_factor_pending {
+        Err(AppError::UNAUTHORIZED)
+    } else {
+        Ok(())
+    }
+}
+
+/// Generates a new TOTP secret for the user. Two-factor authentication isn't
+/// enabled until the user confirms that their authenticator app is set up, by
+/// giving a code generated from the secret; see `enable_two_factor`.
+pub async fn begin_two_factor_enrolment(state: &State, user: &User) -> Result<TwoFactorEnrolment> {
+    let secret = totp::generate_secret();
+    
+    // Replace any previous secret, unless two-factor authentication is
+    // already enabled
+    let result = sqlx::query!(
+        "UPDATE users SET totp_secret = ? WHERE id = ? AND totp_enabled = 0",
+        secret,
+        user.id,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    (result.rows_affected() > 0)
+        .then_some(())
+        .or_400_bad_request()?;
+    
+    Ok(TwoFactorEnrolment {
+        uri: totp::otpauth_uri(ISSUER, &user.email, &secret),
+        secret,
+    })
+}
+
+/// Enables two-factor authentication for the user, if the code is correct
+/// for the secret from `begin_two_factor_enrolment`. The outcome contains a
+/// new set of recovery codes.
+pub async fn enable_two_factor(state: &State, user: &User, code: &str) -> Result<TwoFactorOutcome> {
+    let secret = sqlx::query_scalar!(
+        "SELECT totp_secret FROM users WHERE id = ? AND totp_enabled = 0",
+        user.id,
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .flatten()
+        .or_400_bad_request()?;
+    
+    let Some(step) = totp::verify_code(&secret, code, state.time_now()) else {
+        return Ok(TwoFactorOutcome {
+            recovery_codes: None,
+            error: Some(TwoFactorError::IncorrectCode),
+        });
+    };
+    
+    let recovery_codes = totp::generate_recovery_codes();
+    
+    let mut tx = state.db.begin().await?;
+    
+    sqlx::query!(
+        "UPDATE users SET totp_enabled = 1, totp_last_step = ? WHERE id = ?",
+        step,
+        user.id,
+    )
+        .execute(&mut *tx)
+        .await?;
+    
+    sqlx::query!("DELETE FROM user_recovery_codes WHERE user_id = ?", user.id)
+        .execute(&mut *tx)
+        .await?;
+    
+    for code in &recovery_codes {
+        let code_hash = totp::hash_recovery_code(code);
+        sqlx::query!(
+            "INSERT INTO user_recovery_codes (user_id, code_hash) VALUES (?, ?)",
+            user.id,
+            code_hash,
+        )
+            .execute(&mut *tx)
+            .await?;
+    }
+    
+    tx.commit().await?;
+    
+    log::info!("Enabled two-factor authentication for user #{}", user.id);
+    Ok(TwoFactorOutcome {
+        recovery_codes: Some(recovery_codes),
+        error: None,
+    })
+}
+
+/// Disables two-factor authentication for the user, if the code is correct.
+/// Either a code from their authenticator app or a recovery code is accepted.
+pub async fn disable_two_factor(state: &State, user: &User, code: &str) -> Result<TwoFactorOutcome> {
+    if !check_second_factor(state, user.id, code).await? {
+        return Ok(TwoFactorOutcome {
+            recovery_codes: None,
+            error: Some(TwoFactorError::IncorrectCode),
+        });
+    }
+    
+    let mut tx = state.db.begin().await?;
+    
+    sqlx::query!(
+        "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL
+            WHERE id = ?",
+        user.id,
+    )
+        .execute(&mut *tx)
+        .await?;
+    
+    sqlx::query!("DELETE FROM user_recovery_codes WHERE user_id = ?", user.id)
+        .execute(&mut *tx)
+        .await?;
+    
+    // Sessions still waiting for a second factor might not be the user's, so
+    // they shouldn't be upgraded to full sessions
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ? AND two_factor_pending = 1",
+        user.id,
+    )
+        .execute(&mut *tx)
+        .await?;
+    
+    tx.commit().await?;
+    
+    log::info!("Disabled two-factor authentication for user #{}", user.id);
+    Ok(TwoFactorOutcome {
+        recovery_codes: None,
+        error: None,
+    })
+}
+
+/// Completes the second step of logging in, for a user with two-factor
+/// authentication enabled. Either a code from their authenticator app or a
+/// recovery code is accepted. Returns the fully logged-in user, or an error if
+/// the code is incorrect; after too many incorrect codes, the session is
+/// revoked.
+/// 
+/// Incorrect codes are also rate-limited per account, since revoking the
+/// session alone wouldn't stop an attacker who knows the password from
+/// logging in again to try more codes.
+pub async fn complete_two_factor_login(state: &State, request: &HttpRequest, auth: Auth, code: &str) -> Result<User> {
+    if !auth.user.two_factor_pending {
+        return Ok(auth.user);
+    }
+    
+    // This is keyed by user id rather than email address, so that it isn't
+    // reset by a successful password login
+    let keys = rate_limit::keys_for("two_factor", request, &auth.user.id.to_string());
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
+    if check_second_factor(state, auth.user.id, code).await? {
+        state.rate_limiter.release_attempt(&keys[..1]);
+        state.rate_limiter.reset(&keys[1]);
+        sqlx::query!(
+            "UPDATE sessions SET two_factor_pending = 0 WHERE id = ?",
+            auth.session_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        return Ok(User {
+            two_factor_pending: false,
+            ..auth.user
+        });
+    }
+    
+    let attempts = sqlx::query_scalar!(
+        "UPDATE sessions SET two_factor_attempts = two_factor_attempts + 1
+            WHERE id = ?
+            RETURNING two_factor_attempts",
+        auth.session_id,
+    )
+        .fetch_one(&state.db)
+        .await?;
+    
+    if attempts >= MAX_ATTEMPTS_PER_SESSION {
+        log::info!("Too many incorrect two-factor codes for session #{}; revoking", auth.session_id);
+        auth.logout(state, request)
+            .await?;
+    }
+    
+    Err(AppError::UNAUTHORIZED)
+}
+
+/// Checks a code from the user's authenticator app, or one of their recovery
+/// codes. Each code can only be used once, so that a code which is seen by
+/// someone else can't be replayed.
+async fn check_second_factor(state: &State, user_id: i64, code: &str) -> Result<bool> {
+    let secret = sqlx::query_scalar!(
+        "SELECT totp_secret FROM users WHERE id = ? AND totp_enabled = 1",
+        user_id,
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .flatten();
+    
+    let Some(secret) = secret else {
+        return Ok(false);
+    };
+    
+    if let Some(step) = totp::verify_code(&secret, code, state.time_now()) {
+        let result = sqlx::query!(
+            "UPDATE users SET totp_last_step = ?
+                WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+            step,
+            user_id,
+            step,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        return Ok(result.rows_affected() > 0);
+    }
+    
+    let code_hash = totp::hash_recovery_code(code);
+    let result = sqlx::query!(
+        "DELETE FROM user_recovery_codes WHERE user_id = ? AND code_hash = ?",
+        user_id,
+        code_hash,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{
+        cookie::Cookie,
+        dev::ServiceResponse,
+        http::StatusCode,
+        middleware,
+        test,
+        App,
+    };
+    use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+    use authlogic::{AppDb, PasswordHash};
+    use chrono::TimeDelta;
+    use serde_json::{json, Value};
+    
+    use crate::{
+        auth::totp,
+        mailer::MemoryMailer,
+        model::time,
+        state::{self, insert_test_user, State},
+    };
+    
+    const PASSWORD: &str = "correct horse battery staple";
+    
+    fn session_cookie<B>(state: &State, response: &ServiceResponse<B>) -> Cookie<'static> {
+        response.response()
+            .cookies()
+            .find(|c| c.name() == state.cfg.session_token_cookie_name)
+            .expect("Response should set the session cookie")
+            .into_owned()
+    }
+    
+    #[actix_web::test]
+    async fn two_factor_login_flow() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let start = time::start_of_day(time::now());
+        state.set_time(start);
+        
+        let user = insert_test_user(&state, "alice@example.com").await;
+        let salt = SaltString::generate(rand::thread_rng());
+        let hash = Argon2::default()
+            .hash_password(PASSWORD.as_bytes(), &salt)
+            .unwrap()
+            .to_string();
+        state.update_password(&user, PasswordHash::from(hash), false)
+            .await
+            .unwrap();
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(authlogic::middleware::<State>))
+        ).await;
+        
+        let login = || test::TestRequest::post()
+            .uri("/api/login")
+            .set_json(json!({"email": "alice@example.com", "password": PASSWORD}))
+            .to_request();
+        let post = |uri: &str, cookie: &Cookie<'static>, body: Value| test::TestRequest::post()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .set_json(body)
+            .to_request();
+        
+        // Enrol while logged in with just a password
+        let response = test::call_service(&app, login()).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let cookie = session_cookie(&state, &response);
+        
+        let enrolment: Value = test::call_and_read_body_json(&app, post("/api/two_factor/enrol", &cookie, json!({}))).await;
+        let secret = enrolment["secret"].as_str().unwrap().to_string();
+        
+        let code = totp::generate_code(&secret, start);
+        let outcome: Value = test::call_and_read_body_json(&app, post("/api/two_factor/enable", &cookie, json!({"code": code}))).await;
+        assert!(outcome["recoveryCodes"].is_array(), "{outcome}");
+        
+        // Logging in again needs a second factor
+        let response = test::call_service(&app, login()).await;
+        let cookie = session_cookie(&state, &response);
+        let details: Value = test::read_body_json(response).await;
+        assert_eq!(Some(true), details["twoFactorPending"].as_bool(), "{details}");
+        
+        let response = test::call_service(&app, post("/api/login/two_factor", &cookie, json!({"code": "000000"}))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        
+        // The code used to enable two-factor authentication can't be reused
+        let response = test::call_service(&app, post("/api/login/two_factor", &cookie, json!({"code": code}))).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        
+        let later = start + TimeDelta::seconds(60);
+        state.set_time(later);
+        let code = totp::generate_code(&secret, later);
+        let response = test::call_service(&app, post("/api/login/two_factor", &cookie, json!({"code": code}))).await;
+        assert_eq!(StatusCode::OK, response.status());
+        
+        // Incorrect codes are throttled per account, even from different IP
+        // addresses, and logging in again doesn't allow more attempts
+        let two_factor_from = |ip: &str, cookie: &Cookie<'static>, code: &str| test::TestRequest::post()
+            .uri("/api/login/two_factor")
+            .peer_addr(format!("{ip}:1234").parse().unwrap())
+            .cookie(cookie.clone())
+            .set_json(json!({"code": code}))
+            .to_request();
+        
+        let response = test::call_service(&app, login()).await;
+        let cookie = session_cookie(&state, &response);
+        for i in 0..state.cfg.rate_limit_free_attempts {
+            let response = test::call_service(&app, two_factor_from(&format!("192.0.2.{i}"), &cookie, "000000")).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        }
+        
+        let response = test::call_service(&app, login()).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let cookie = session_cookie(&state, &response);
+        
+        let even_later = later + TimeDelta::seconds(60);
+        state.set_time(even_later);
+        let code = totp::generate_code(&secret, even_later);
+        let response = test::call_service(&app, two_factor_from("198.51.100.1", &cookie, &code)).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    }
+}
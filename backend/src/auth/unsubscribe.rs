@@ -0,0 +1,56 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::state::State;
+
+/// The optional emails which a user can unsubscribe from by following a link,
+/// without logging in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum MailingList {
+    #[serde(rename = "reminders")]
+    Reminders,
+    #[serde(rename = "digest")]
+    Digest,
+}
+
+impl MailingList {
+    fn name(self) -> &'static str {
+        match self {
+            MailingList::Reminders => "reminders",
+            MailingList::Digest => "digest",
+        }
+    }
+}
+
+/// Builds a link which unsubscribes the user from a mailing list when
+/// visited. The link contains a token signed with the server's signing key,
+/// so it can't be forged for another user or another list.
+pub fn unsubscribe_link(state: &State, user_id: i64, list: MailingList) -> String {
+    let signature = URL_SAFE_NO_PAD.encode(sign(state, user_id, list).finalize().into_bytes());
+    
+    // This must match the `unsubscribe` route URL
+    format!("{}unsubscribe/{}?token={user_id}.{signature}", state.cfg.base_url, list.name())
+}
+
+/// Checks a token from an unsubscribe link. Returns the id of the user to
+/// unsubscribe, or `None` if the token is invalid.
+pub fn verify_unsubscribe_token(state: &State, list: MailingList, token: &str) -> Option<i64> {
+    let (user_id, signature) = token.split_once('.')?;
+    let user_id = user_id.parse().ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    
+    // `verify_slice` compares in constant time
+    sign(state, user_id, list)
+        .verify_slice(&signature)
+        .ok()?;
+    
+    Some(user_id)
+}
+
+fn sign(state: &State, user_id: i64, list: MailingList) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(state.cfg.signing_key.as_bytes())
+        .expect("HMAC should accept keys of any length");
+    mac.update(format!("unsubscribe:{}:{user_id}", list.name()).as_bytes());
+    mac
+}
@@ -1,5 +1,8 @@
+use authlogic::Secret;
+
 use crate::{
-    model::User,
+    auth::{Auth, CustomChallenge},
+    model::{Session, User},
     result::{AppError, Result},
     state::State,
 };
@@ -29,8 +32,6 @@ pub enum RegistrationError {
     MalformedEmail,
     #[serde(rename = "Email address already in use")]
     EmailAlreadyExists,
-    #[serde(rename = "Failed to send an email with the verification link")]
-    EmailFailed,
     #[serde(rename = "Please choose a display name")]
     MissingDisplayName,
     #[serde(rename = "Please choose a password of at least 8 characters")]
@@ -63,14 +64,7 @@ pub async fn register(state: &State, user: User, password: authlogic::Secret) ->
     }
     
     // Check if this email is already in use
-    let email_already_exists = sqlx::query_scalar!(
-        "SELECT COUNT(1) FROM users WHERE email = ?",
-        user.email,
-    )
-        .fetch_one(&state.db)
-        .await?;
-    
-    if email_already_exists > 0 {
+    if email_already_exists(state, &user.email).await? {
         return error_outcome(RegistrationError::EmailAlreadyExists);
     }
     
@@ -83,7 +77,298 @@ pub async fn register(state: &State, user: User, password: authlogic::Secret) ->
         }),
         
         Err(AppError::Auth(authlogic::Error::PasswordTooShort)) => error_outcome(RegistrationError::PasswordTooShort),
-        Err(AppError::Mail(_)) => error_outcome(RegistrationError::EmailFailed),
         Err(e) => Err(e),
     }
 }
+
+/// Represents an error preventing a change to a user's account details, which
+/// should be resolved by the user entering different details.
+#[derive(serde::Serialize)]
+pub enum AccountUpdateError {
+    #[serde(rename = "Malformed email address")]
+    MalformedEmail,
+    #[serde(rename = "Email address already in use")]
+    EmailAlreadyExists,
+    #[serde(rename = "Incorrect password")]
+    IncorrectPassword,
+    #[serde(rename = "Please choose a display name")]
+    MissingDisplayName,
+    #[serde(rename = "Please choose a password of at least 8 characters")]
+    PasswordTooShort,
+    #[serde(rename = "Please choose a different password to your current one")]
+    PasswordsNotDifferent,
+}
+
+#[derive(serde::Serialize)]
+pub struct AccountUpdateOutcome {
+    pub error: Option<AccountUpdateError>,
+}
+
+impl From<Option<AccountUpdateError>> for AccountUpdateOutcome {
+    fn from(error: Option<AccountUpdateError>) -> Self {
+        Self {error}
+    }
+}
+
+/// Changes the authenticated user's password. The user's current password is
+/// required if they have one, and they will be sent an email notification
+/// about the change. Unless `keep_other_sessions` is set, the user's other
+/// sessions are revoked, in case someone else has access to their account.
+pub async fn change_password(state: &State, auth: Auth, old_password: Option<Secret>, new_password: Secret, keep_other_sessions: bool) -> Result<AccountUpdateOutcome> {
+    let (user_id, session_id) = (auth.user.id, auth.session_id);
+    let result = authlogic::change_password(state, auth, old_password, new_password).await;
+    
+    let error = match result {
+        Ok(()) => {
+            if !keep_other_sessions {
+                Session::revoke_all_others(state, user_id, session_id)
+                    .await?;
+            }
+            None
+        },
+        Err(AppError::Auth(authlogic::Error::IncorrectPassword)) => Some(AccountUpdateError::IncorrectPassword),
+        Err(AppError::Auth(authlogic::Error::PasswordTooShort)) => Some(AccountUpdateError::PasswordTooShort),
+        Err(AppError::Auth(authlogic::Error::PasswordsNotDifferent)) => Some(AccountUpdateError::PasswordsNotDifferent),
+        Err(e) => return Err(e),
+    };
+    
+    Ok(error.into())
+}
+
+/// Changes the user's display name.
+pub async fn update_profile(state: &State, user: &User, display_name: &str) -> Result<AccountUpdateOutcome> {
+    // Repeat client-side checks, since we don't necessarily trust that
+    // they were done.
+    if display_name.is_empty() {
+        return Ok(Some(AccountUpdateError::MissingDisplayName).into());
+    }
+    
+    user.update_display_name(state, display_name)
+        .await?;
+    
+    Ok(None.into())
+}
+
+/// Sends a challenge to the new email address, to verify that it belongs to
+/// the user. The user's email address is only changed once they complete the
+/// challenge. As when changing password, the user's current password is
+/// required if they have one, since control of the email address means
+/// control of the account.
+pub async fn request_email_change(state: &State, user: &User, password: Option<Secret>, new_email: String) -> Result<AccountUpdateOutcome> {
+    if !is_current_password(state, user, password).await? {
+        return Ok(Some(AccountUpdateError::IncorrectPassword).into());
+    }
+    
+    // Repeat client-side checks, since we don't necessarily trust that
+    // they were done.
+    if !new_email.contains('@') {
+        return Ok(Some(AccountUpdateError::MalformedEmail).into());
+    } else if email_already_exists(state, &new_email).await? {
+        return Ok(Some(AccountUpdateError::EmailAlreadyExists).into());
+    }
+    
+    let challenge = CustomChallenge::ChangeEmail {new_email};
+    authlogic::mail::issue_custom_challenge(state, user, challenge)
+        .await?;
+    
+    Ok(None.into())
+}
+
+/// Changes the user's email address, after they complete the challenge sent
+/// to the new address, and notifies them at the old address. Returns `false`
+/// if the new address has been taken by another user in the meantime.
+pub async fn complete_email_change(state: &State, mut user: User, new_email: String) -> Result<bool> {
+    let old_email = std::mem::replace(&mut user.email, new_email);
+    
+    if !user.update_email(state).await? {
+        return Ok(false);
+    }
+    
+    // The change has already been made, so it has succeeded even if the
+    // notification can't be sent
+    if let Err(e) = crate::auth::mail::send_email_changed_notification(state, &user, &old_email).await {
+        log::error!("Failed to notify user #{} of their email change: {e:?}", user.id);
+    }
+    Ok(true)
+}
+
+/// Determines whether `password` is the user's current password. A user
+/// without a password, e.g. one who only logs in with an OpenID Connect
+/// provider, doesn't need to give one.
+async fn is_current_password(state: &State, user: &User, password: Option<Secret>) -> Result<bool> {
+    use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+    use authlogic::AppDb;
+    
+    let Some(data) = state.get_user_data_by_id(user.id).await? else {
+        return Ok(false);
+    };
+    let Some(stored_hash) = data.password_hash.expose().map(str::to_string) else {
+        return Ok(true);
+    };
+    let Some(password) = password else {
+        return Ok(false);
+    };
+    
+    // Passwords are hashed by authlogic, which uses Argon2 with the default
+    // parameters; the parameters are also encoded in the hash itself. Hashing
+    // is deliberately slow, so it mustn't block the worker thread.
+    let user_id = user.id;
+    let is_correct = actix_web::rt::task::spawn_blocking(move || {
+        let Ok(stored_hash) = PasswordHash::new(&stored_hash) else {
+            log::error!("User #{user_id} has a malformed password hash");
+            return false;
+        };
+        
+        Argon2::default().verify_password(password.expose().as_bytes(), &stored_hash).is_ok()
+    })
+        .await
+        .map_err(std::io::Error::other)?;
+    
+    Ok(is_correct)
+}
+
+/// Determines whether any user, verified or not, has this email address. The
+/// `users.email` column is declared with `NOCASE`, so the comparison is
+/// case-insensitive.
+async fn email_already_exists(state: &State, email: &str) -> Result<bool> {
+    let count = sqlx::query_scalar!(
+        "SELECT COUNT(1) FROM users WHERE email = ?",
+        email,
+    )
+        .fetch_one(&state.db)
+        .await?;
+    
+    Ok(count > 0)
+}
+
+/// Issues a password reset challenge to the user with this email address.
+/// Nothing is sent if there is no such verified user, or if they already
+/// have too many pending challenges; the caller should not reveal which.
+pub async fn request_password_reset(state: &State, email: &str) -> Result<()> {
+    if let Some(user) = get_user_for_challenge(state, email).await? {
+        authlogic::request_password_reset(state, &user)
+            .await?;
+    }
+    
+    Ok(())
+}
+
+/// Issues a login challenge to the user with this email address, subject to
+/// the same conditions as `request_password_reset`.
+pub async fn request_login_link(state: &State, email: &str) -> Result<()> {
+    if let Some(user) = get_user_for_challenge(state, email).await? {
+        authlogic::mail::issue_login_challenge(state, &user)
+            .await?;
+    }
+    
+    Ok(())
+}
+
+/// Gets the verified user with this email address, if they exist and are
+/// allowed to be sent another challenge.
+async fn get_user_for_challenge(state: &State, email: &str) -> Result<Option<User>> {
+    use authlogic::AppDb;
+    
+    let Some(data) = state.get_user_data_by_identifier(email).await? else {
+        return Ok(None);
+    };
+    
+    if data.state.require_email_verification {
+        return Ok(None);
+    }
+    
+    // Limit the number of emails which can be sent to one address, so that
+    // this can't be used to flood someone's inbox.
+    let pending = state.count_pending_challenges(data.user.id)
+        .await?;
+    if pending >= state.cfg.max_pending_challenges {
+        log::info!("User #{} has too many pending challenges", data.user.id);
+        return Ok(None);
+    }
+    
+    Ok(Some(data.user))
+}
+
+/// Suspends a user, revoking all of their sessions, and notifies them of the
+/// reason. Their data is kept, so the suspension can be reversed.
+pub async fn suspend_user(state: &State, user: &User, reason: &str) -> Result<()> {
+    state.set_user_suspended(user.id, Some(reason))
+        .await?;
+    
+    log::info!("Suspended user #{}: {reason}", user.id);
+    crate::auth::mail::send_suspended_notification(state, user, reason)
+        .await
+}
+
+/// Reverses a user's suspension, and notifies them.
+pub async fn unsuspend_user(state: &State, user: &User) -> Result<()> {
+    state.set_user_suspended(user.id, None)
+        .await?;
+    
+    log::info!("Unsuspended user #{}", user.id);
+    crate::auth::mail::send_unsuspended_notification(state, user)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, middleware, test, App};
+    use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+    use authlogic::{AppDb, PasswordHash};
+    use serde_json::{json, Value};
+    
+    use crate::{
+        mailer::MemoryMailer,
+        state::{self, insert_test_user, State},
+    };
+    
+    const PASSWORD: &str = "correct horse battery staple";
+    
+    #[actix_web::test]
+    async fn email_change_is_rate_limited() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
+        let salt = SaltString::generate(rand::thread_rng());
+        let hash = Argon2::default()
+            .hash_password(PASSWORD.as_bytes(), &salt)
+            .unwrap()
+            .to_string();
+        state.update_password(&user, PasswordHash::from(hash), false)
+            .await
+            .unwrap();
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(authlogic::middleware::<State>))
+        ).await;
+        
+        let request = test::TestRequest::post()
+            .uri("/api/login")
+            .set_json(json!({"email": "alice@example.com", "password": PASSWORD}))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let cookie = response.response()
+            .cookies()
+            .find(|c| c.name() == state.cfg.session_token_cookie_name)
+            .expect("Response should set the session cookie")
+            .into_owned();
+        
+        let change_email = |password: &str| test::TestRequest::post()
+            .uri("/api/change_email")
+            .cookie(cookie.clone())
+            .set_json(json!({"password": password, "newEmail": "bob@example.com"}))
+            .to_request();
+        
+        let outcome: Value = test::call_and_read_body_json(&app, change_email(PASSWORD)).await;
+        assert_eq!(Value::Null, outcome["error"]);
+        
+        for _ in 0..state.cfg.rate_limit_free_attempts {
+            let outcome: Value = test::call_and_read_body_json(&app, change_email("wrong")).await;
+            assert_eq!("Incorrect password", outcome["error"]);
+        }
+        let response = test::call_service(&app, change_email(PASSWORD)).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    fs::{File, TryLockError},
+    io::{Error as IoError, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    Connection,
+    SqliteConnection,
+};
+
+use crate::{
+    config::Config,
+    model::time,
+    result::Result,
+    state::State,
+};
+
+/// Backup files are named with this prefix and extension, and the time they
+/// were taken. Other files in the backup directory are left alone.
+const BACKUP_PREFIX: &str = "tsumego-";
+const BACKUP_EXTENSION: &str = ".db";
+
+/// Takes a backup of the database in the configured backup directory, and
+/// deletes the oldest backups beyond the configured number to keep. This
+/// function will be called periodically.
+pub async fn run_scheduled_backup(state: &State) -> Result<()> {
+    let Some(dir) = &state.cfg.backup_dir else {
+        return Ok(());
+    };
+    
+    let path = create_backup(&state.db, Path::new(dir.as_ref()))
+        .await?;
+    log::info!("Backed up database to {}", path.display());
+    
+    prune_backups(Path::new(dir.as_ref()), state.cfg.backup_retention.get())
+}
+
+/// Writes a consistent copy of the database to a new file in `dir`, while the
+/// database is in use, and checks the copy's integrity. Returns the path of
+/// the new backup.
+async fn create_backup(db: &SqlitePool, dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    
+    let timestamp = time::now().format("%Y%m%d-%H%M%S-%3f");
+    let path = dir.join(format!("{BACKUP_PREFIX}{timestamp}{BACKUP_EXTENSION}"));
+    let path_str = path.to_string_lossy();
+    
+    sqlx::query!("VACUUM INTO ?", path_str)
+        .execute(db)
+        .await?;
+    
+    if let Err(e) = check_integrity(&path).await {
+        // Don't keep a bad backup, which might be restored later
+        std::fs::remove_file(&path)?;
+        return Err(e);
+    }
+    
+    Ok(path)
+}
+
+/// Deletes the oldest backups in `dir`, so that at most `retention` remain.
+fn prune_backups(dir: &Path, retention: usize) -> Result<()> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_backup = path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_EXTENSION));
+        
+        if is_backup {
+            backups.push(path);
+        }
+    }
+    
+    // The names contain timestamps, so sorting them puts the oldest first
+    backups.sort();
+    let excess = backups.len().saturating_sub(retention);
+    for path in &backups[..excess] {
+        std::fs::remove_file(path)?;
+        log::info!("Deleted old backup {}", path.display());
+    }
+    
+    Ok(())
+}
+
+/// Runs SQLite's integrity check on a database file, without modifying it.
+/// Returns an error if the file isn't a valid database.
+async fn check_integrity(path: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options)
+        .await?;
+    
+    let problems: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&mut conn)
+        .await?;
+    conn.close()
+        .await?;
+    
+    if problems != ["ok"] {
+        let detail = format!("Integrity check failed for {}: {}", path.display(), problems.join("; "));
+        return Err(IoError::new(ErrorKind::InvalidData, detail).into());
+    }
+    
+    Ok(())
+}
+
+/// Takes a shared lock on the database, which a server holds while it is
+/// running, so that a backup can't be restored underneath it. The lock is
+/// released when the returned file is closed.
+pub fn lock_for_server(cfg: &Config) -> Result<File> {
+    let lock = open_lock_file(cfg)?;
+    match lock.try_lock_shared() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(IoError::new(ErrorKind::WouldBlock, "A backup is being restored").into()),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Opens the file which is locked to show that the database is in use. It is
+/// next to the database file, and is never deleted.
+fn open_lock_file(cfg: &Config) -> Result<File> {
+    let mut path = database_path(cfg)?.into_os_string();
+    path.push(".lock");
+    
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    Ok(file)
+}
+
+fn database_path(cfg: &Config) -> Result<PathBuf> {
+    let options = SqliteConnectOptions::from_str(&cfg.database_url)?;
+    Ok(options.get_filename().to_path_buf())
+}
+
+/// Replaces the database with a backup. This fails if a server is running,
+/// since it would still be using the old database. A backup of the current
+/// database is taken first, in the configured backup directory, so a mistaken
+/// restore can be undone.
+pub async fn restore(cfg: &Config, backup: &Path) -> Result<()> {
+    // The lock is held until the restore is finished, so a server can't start
+    // in the meantime
+    let lock = open_lock_file(cfg)?;
+    match lock.try_lock() {
+        Ok(()) => {},
+        Err(TryLockError::WouldBlock) => return Err(IoError::new(ErrorKind::WouldBlock, "The server is running; stop it first").into()),
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    
+    check_integrity(backup)
+        .await?;
+    
+    let db_path = database_path(cfg)?;
+    if db_path.exists() {
+        let dir = cfg.backup_dir.as_deref().unwrap_or(".");
+        let options = SqliteConnectOptions::from_str(&cfg.database_url)?;
+        let db = SqlitePool::connect_with(options)
+            .await?;
+        let path = create_backup(&db, Path::new(dir))
+            .await?;
+        db.close().await;
+        println!("Backed up current database to {}", path.display());
+    }
+    
+    // Copy the backup next to the database and check the copy, so that the
+    // database is only ever replaced by a whole, valid file
+    let mut temp_path = db_path.clone().into_os_string();
+    temp_path.push(".restoring");
+    let temp_path = PathBuf::from(temp_path);
+    std::fs::copy(backup, &temp_path)?;
+    if let Err(e) = check_integrity(&temp_path).await {
+        std::fs::remove_file(&temp_path)?;
+        return Err(e);
+    }
+    
+    // Stale journal files would be applied to the restored database
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let mut journal = db_path.clone().into_os_string();
+        journal.push(suffix);
+        if let Err(e) = std::fs::remove_file(&journal) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    }
+    
+    // A rename within a directory is atomic
+    std::fs::rename(&temp_path, &db_path)?;
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+    
+    use super::{check_integrity, create_backup, lock_for_server, prune_backups, restore};
+    use crate::config::Config;
+    
+    #[actix_web::test]
+    async fn backups_are_checked_and_pruned() {
+        let dir = std::env::temp_dir().join(format!("tsumego-backups-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        
+        // `VACUUM INTO` doesn't write files from an in-memory database, so
+        // back up a database file instead
+        let options = SqliteConnectOptions::new()
+            .filename(dir.join("source.sqlite"))
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query("CREATE TABLE example (id INTEGER PRIMARY KEY)")
+            .execute(&db)
+            .await
+            .unwrap();
+        
+        let first = create_backup(&db, &dir).await.unwrap();
+        let second = create_backup(&db, &dir).await.unwrap();
+        db.close().await;
+        assert!(check_integrity(&second).await.is_ok());
+        
+        // A file which isn't a database fails the check
+        let junk = dir.join("junk.db");
+        std::fs::write(&junk, "not a database").unwrap();
+        assert!(check_integrity(&junk).await.is_err());
+        
+        prune_backups(&dir, 1).unwrap();
+        let first_exists = first.exists();
+        let second_exists = second.exists();
+        let junk_exists = junk.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        
+        assert!(!first_exists);
+        assert!(second_exists);
+        // Only files named like backups are pruned
+        assert!(junk_exists);
+    }
+    
+    #[actix_web::test]
+    async fn restore_replaces_stopped_database() {
+        let dir = std::env::temp_dir().join(format!("tsumego-restore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        
+        let mut cfg = Config::for_test();
+        let db_path = dir.join("live.sqlite");
+        cfg.database_url = format!("sqlite://{}", db_path.display()).into();
+        cfg.backup_dir = Some(dir.join("backups").to_string_lossy().into_owned().into());
+        
+        // A backup of one database is restored over another
+        let mut tables = Vec::new();
+        for (path, table) in [(dir.join("old.sqlite"), "restored"), (db_path.clone(), "replaced")] {
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true);
+            let db = SqlitePool::connect_with(options).await.unwrap();
+            sqlx::query(&format!("CREATE TABLE {table} (id INTEGER PRIMARY KEY)"))
+                .execute(&db)
+                .await
+                .unwrap();
+            tables.push(db);
+        }
+        let backup = create_backup(&tables[0], &dir).await.unwrap();
+        for db in tables {
+            db.close().await;
+        }
+        
+        // Not while a server is running
+        let server_lock = lock_for_server(&cfg).unwrap();
+        let while_running = restore(&cfg, &backup).await;
+        drop(server_lock);
+        let after_stopping = restore(&cfg, &backup).await;
+        
+        let options = SqliteConnectOptions::new()
+            .filename(&db_path)
+            .read_only(true);
+        let db = SqlitePool::connect_with(options).await.unwrap();
+        let names: Vec<String> = sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        db.close().await;
+        let num_backups = std::fs::read_dir(dir.join("backups")).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        
+        assert!(while_running.is_err());
+        assert!(after_stopping.is_ok(), "{after_stopping:?}");
+        assert_eq!(vec!["restored"], names);
+        // The replaced database was backed up first
+        assert_eq!(1, num_backups);
+    }
+}
@@ -1,3 +1,7 @@
+use std::{net::IpAddr, num::NonZeroUsize};
+
+use actix_web::HttpRequest;
+
 type CowStr = std::borrow::Cow<'static, str>;
 
 /// The application's config parameters, which are loaded from the environment
@@ -6,21 +10,92 @@ type CowStr = std::borrow::Cow<'static, str>;
 pub struct Config {
     pub host_addr: CowStr,
     pub host_port: u16,
+    /// The address of a reverse proxy in front of the server, if any. The
+    /// `X-Forwarded-For` header is only trusted in requests from this
+    /// address, since any client could send it.
+    pub trusted_proxy: Option<IpAddr>,
     
     pub base_url: CowStr,
     pub email_from: CowStr,
+    pub mail_transport: MailTransport,
+    /// The maildir which emails are written to, if `mail_transport` is
+    /// `file`.
+    pub mail_dir: Option<CowStr>,
+    pub mail_queue_interval_secs: u64,
+    pub mail_max_attempts: i64,
+    
+    pub smtp_host: CowStr,
+    pub smtp_port: u16,
+    pub smtp_tls: SmtpTls,
+    pub smtp_username: Option<CowStr>,
+    pub smtp_password: Option<CowStr>,
+    pub smtp_pool_size: u32,
     
     pub database_url: CowStr,
     pub database_pool_size: u32,
+    /// Whether to apply pending database migrations when the server starts.
+    /// If not, the server refuses to start until they are applied.
+    pub database_auto_migrate: bool,
+    /// The directory which database backups are written to. Scheduled
+    /// backups are only taken if this is set.
+    pub backup_dir: Option<CowStr>,
+    /// The number of scheduled backups to keep; older ones are deleted. This
+    /// can't be zero, which would delete each backup as soon as it is taken.
+    pub backup_retention: NonZeroUsize,
     
     pub session_token_cookie_name: CowStr,
     pub session_duration_days: i64,
     pub session_renew_after_days: i64,
     
+    pub max_pending_challenges: i64,
+    
+    /// The key used to sign tokens in links, such as unsubscribe links.
+    pub signing_key: CowStr,
+    
+    pub rate_limit_free_attempts: u32,
+    pub rate_limit_base_delay_secs: u64,
+    pub rate_limit_max_delay_secs: u64,
+    
+    /// Logging in through an external OpenID Connect provider is only
+    /// enabled if all of these are set, except the client secret.
+    pub oidc_issuer: Option<CowStr>,
+    pub oidc_authorization_endpoint: Option<CowStr>,
+    pub oidc_token_endpoint: Option<CowStr>,
+    pub oidc_userinfo_endpoint: Option<CowStr>,
+    pub oidc_client_id: Option<CowStr>,
+    pub oidc_client_secret: Option<CowStr>,
+    
     pub max_problems_at_once: i64,
     pub srs_interval_fuzz_factor: f64,
 }
 
+/// How emails are delivered; see `crate::mailer`.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Send mail through the SMTP relay.
+    Smtp,
+    /// Write mail to files in a maildir.
+    File,
+    /// Only log the mail which would be sent.
+    Log,
+    /// Keep mail in memory; only useful for testing.
+    Memory,
+}
+
+/// How the connection to the SMTP relay is encrypted.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// No encryption. This should only be used for a relay on the same host.
+    None,
+    /// Connect without encryption, then upgrade the connection using the
+    /// `STARTTLS` command. This is usually on port 587.
+    StartTls,
+    /// Connect with TLS from the start. This is usually on port 465.
+    Tls,
+}
+
 impl Config {
     pub fn get_from_env() -> Self {
         envy::from_env()
@@ -29,4 +104,40 @@ impl Config {
                 std::process::exit(1);
             })
     }
+    
+    /// Loads the config from `.env`, for tests.
+    #[cfg(test)]
+    pub fn for_test() -> Self {
+        let vars = dotenvy::from_filename_iter(".env")
+            .expect("Failed to load environment variables from '.env'")
+            .map(|var| var.expect("Invalid line in '.env'"));
+        envy::from_iter(vars)
+            .expect("Failed to load config from '.env'")
+    }
+    
+    /// Determines the IP address of the client making a request, e.g. for
+    /// rate limiting. This is the address of the connection, unless the
+    /// connection is from the trusted proxy, in which case the address it
+    /// forwarded the request from is used.
+    pub fn client_ip(&self, request: &HttpRequest) -> Option<String> {
+        let peer_ip = request.peer_addr()?.ip();
+        
+        if self.trusted_proxy == Some(peer_ip) {
+            // The proxy appends the address which it received the request
+            // from; any earlier addresses were sent by the client, so might
+            // be made up
+            let forwarded_ip = request.headers()
+                .get("X-Forwarded-For")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.rsplit(',').next())
+                .map(str::trim)
+                .filter(|ip| !ip.is_empty());
+            
+            if let Some(forwarded_ip) = forwarded_ip {
+                return Some(forwarded_ip.to_string());
+            }
+        }
+        
+        Some(peer_ip.to_string())
+    }
 }
@@ -0,0 +1,316 @@
+use chrono::{Datelike, NaiveDate, TimeDelta, Timelike, Weekday};
+
+use crate::{
+    auth::MailingList,
+    model::{time, Subscriber, UserPreferences},
+    result::Result,
+    state::State,
+};
+
+/// The day of the week on which digests are sent, in each user's local time.
+const DIGEST_WEEKDAY: Weekday = Weekday::Mon;
+
+/// The local time of day after which digests are sent, in minutes after
+/// midnight.
+const DIGEST_TIME: i64 = 8 * 60;
+
+/// The number of days covered by a digest, and by its forecast.
+const DAYS_PER_WEEK: i64 = 7;
+
+/// A summary of a user's progress over the past week, and the reviews they
+/// have coming up.
+#[derive(serde::Serialize)]
+pub struct WeeklyDigest {
+    /// The number of reviews done in the past week.
+    pub reviews_done: i64,
+    
+    /// The percentage of the past week's reviews which weren't graded
+    /// "Again", or `None` if there were no reviews.
+    pub retention_percent: Option<i64>,
+    
+    /// The number of tsumego reviewed for the first time in the past week.
+    pub new_learned: i64,
+    
+    /// The number of consecutive days, up to today or yesterday, on which the
+    /// user has done at least one review.
+    pub streak_days: i64,
+    
+    /// The number of reviews due on each of the coming days, starting today.
+    /// Overdue reviews are counted as due today.
+    pub forecast: Vec<ForecastDay>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ForecastDay {
+    pub day: String,
+    pub reviews_due: i64,
+}
+
+impl WeeklyDigest {
+    /// Computes the digest for the week before the user's current local day.
+    async fn for_user(state: &State, user_id: i64, prefs: &UserPreferences, now: time::DateTime) -> Result<Self> {
+        let local_now = prefs.local_time(now);
+        let today = local_now.date();
+        
+        // The start of the user's day, in UTC
+        let end = time::start_of_day(local_now) - TimeDelta::minutes(prefs.utc_offset_minutes);
+        let start = end - TimeDelta::days(DAYS_PER_WEEK);
+        let forecast_end = end + TimeDelta::days(DAYS_PER_WEEK);
+        
+        // SQLite date modifier which converts UTC times to the user's local
+        // time
+        let offset = format!("{:+} minutes", prefs.utc_offset_minutes);
+        
+        let reviews = sqlx::query!(
+            r#"SELECT COUNT(1) AS "done!: i64", COALESCE(SUM(grade > 0), 0) AS "remembered!: i64"
+                FROM user_tsumego_reviews
+                WHERE user_id = ? AND review_date >= ? AND review_date < ?"#,
+            user_id,
+            start,
+            end,
+        )
+            .fetch_one(&state.db)
+            .await?;
+        
+        let new_learned = sqlx::query_scalar!(
+            r#"SELECT COUNT(1) AS "count!: i64" FROM (
+                SELECT MIN(review_date) AS first_review FROM user_tsumego_reviews
+                    WHERE user_id = ?
+                    GROUP BY tsumego_id
+            ) WHERE first_review >= ? AND first_review < ?"#,
+            user_id,
+            start,
+            end,
+        )
+            .fetch_one(&state.db)
+            .await?;
+        
+        let review_days = sqlx::query_scalar!(
+            r#"SELECT DISTINCT DATE(review_date, ?) AS "day!: NaiveDate"
+                FROM user_tsumego_reviews
+                WHERE user_id = ?
+                ORDER BY 1 DESC"#,
+            offset,
+            user_id,
+        )
+            .fetch_all(&state.db)
+            .await?;
+        
+        let due_days = sqlx::query!(
+            r#"SELECT DATE(review_due, ?) AS "day!: NaiveDate", COUNT(1) AS "count!: i64"
+                FROM user_tsumego_stats
+                WHERE user_id = ? AND review_due < ?
+                GROUP BY 1"#,
+            offset,
+            user_id,
+            forecast_end,
+        )
+            .fetch_all(&state.db)
+            .await?;
+        
+        let forecast = today.iter_days()
+            .take(DAYS_PER_WEEK as usize)
+            .map(|day| ForecastDay {
+                day: day.format("%a %-d %b").to_string(),
+                reviews_due: due_days.iter()
+                    .filter(|d| d.day == day || (day == today && d.day < today))
+                    .map(|d| d.count)
+                    .sum(),
+            })
+            .collect();
+        
+        Ok(Self {
+            reviews_done: reviews.done,
+            retention_percent: (reviews.done > 0)
+                .then(|| (100 * reviews.remembered + reviews.done / 2) / reviews.done),
+            new_learned,
+            streak_days: streak_length(&review_days, today),
+            forecast,
+        })
+    }
+}
+
+/// Counts the consecutive days ending today or yesterday which appear in
+/// `days`. The days must be distinct and in descending order.
+fn streak_length(days: &[NaiveDate], today: NaiveDate) -> i64 {
+    let yesterday = today.pred_opt()
+        .expect("Today should not be the earliest representable date");
+    let mut expected = if days.first() == Some(&today) { today } else { yesterday };
+    
+    let mut streak = 0;
+    for &day in days {
+        if day > expected {
+            continue;
+        } else if day < expected {
+            break;
+        }
+        streak += 1;
+        let Some(previous) = expected.pred_opt() else {
+            break;
+        };
+        expected = previous;
+    }
+    streak
+}
+
+/// Sends a weekly digest email to each user who has opted in, once per week
+/// on the digest day in their local time. This function will be called
+/// periodically.
+pub async fn send_due_digests(state: &State) -> Result<()> {
+    send_due_digests_at(state, time::now())
+        .await
+}
+
+async fn send_due_digests_at(state: &State, now: time::DateTime) -> Result<()> {
+    let subscribers = UserPreferences::get_subscribers(state, MailingList::Digest)
+        .await?;
+    
+    for subscriber in subscribers {
+        // One user's failure shouldn't hold up everyone else's digests
+        let user_id = subscriber.user.id;
+        if let Err(e) = send_digest_if_due(state, subscriber, now).await {
+            log::error!("Failed to send a digest to user #{user_id}: {e:?}");
+        }
+    }
+    
+    Ok(())
+}
+
+async fn send_digest_if_due(state: &State, subscriber: Subscriber, now: time::DateTime) -> Result<()> {
+    let Subscriber {user, prefs, last_sent_date} = subscriber;
+    let local_now = prefs.local_time(now);
+    let local_date = local_now.date();
+    
+    let minutes_after_midnight = i64::from(local_now.hour() * 60 + local_now.minute());
+    if local_date.weekday() != DIGEST_WEEKDAY
+        || minutes_after_midnight < DIGEST_TIME
+        || last_sent_date.is_some_and(|d| d >= local_date)
+    {
+        return Ok(());
+    }
+    
+    // Compute the digest before claiming it, so that an error doesn't
+    // cause this week's digest to be skipped
+    let digest = WeeklyDigest::for_user(state, user.id, &prefs, now)
+        .await?;
+    
+    // Claim this week's digest before sending it, so that if this job
+    // runs twice at once, only one of them sends it
+    let claimed = sqlx::query!(
+        "UPDATE user_preferences SET last_digest_date = ?
+            WHERE user_id = ? AND (last_digest_date IS NULL OR last_digest_date < ?)",
+        local_date,
+        user.id,
+        local_date,
+    )
+        .execute(&state.db)
+        .await?
+        .rows_affected() > 0;
+    
+    if !claimed {
+        return Ok(());
+    }
+    
+    let result = crate::auth::send_weekly_digest(state, &user, &digest)
+        .await;
+    if result.is_err() {
+        // Release the claim, so the digest is sent next time this job runs
+        sqlx::query!(
+            "UPDATE user_preferences SET last_digest_date = ? WHERE user_id = ?",
+            last_sent_date,
+            user.id,
+        )
+            .execute(&state.db)
+            .await?;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+    
+    use super::{send_due_digests_at, streak_length};
+    use crate::{
+        mailer::MemoryMailer,
+        model::{time, UserPreferences},
+        state::{self, insert_test_user, State},
+    };
+    
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+    
+    fn at(day: u32, hour: u32) -> time::DateTime {
+        date(day).and_hms_opt(hour, 0, 0).unwrap()
+    }
+    
+    #[test]
+    fn streak_counts_consecutive_days() {
+        let today = date(10);
+        assert_eq!(0, streak_length(&[], today));
+        assert_eq!(3, streak_length(&[date(10), date(9), date(8), date(6)], today));
+        // A streak isn't broken until a whole day is missed
+        assert_eq!(2, streak_length(&[date(9), date(8), date(6)], today));
+        assert_eq!(0, streak_length(&[date(8), date(7)], today));
+    }
+    
+    async fn user_with_digest(state: &State) -> i64 {
+        let user_id = insert_test_user(state, "alice@example.com")
+            .await
+            .id;
+        
+        let prefs = UserPreferences {
+            digest_enabled: true,
+            ..UserPreferences::default()
+        };
+        prefs.save(state, user_id)
+            .await
+            .unwrap();
+        
+        user_id
+    }
+    
+    #[actix_web::test]
+    async fn digest_is_sent_once_per_week() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user_id = user_with_digest(&state).await;
+        
+        sqlx::query!("INSERT INTO tsumego (id, name, board, tree) VALUES (1, 'a', '', ''), (2, 'b', '', '')")
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        // Two reviews on Saturday and one on Sunday, one of them forgotten
+        for (tsumego_id, review_date, grade) in [(1, at(6, 12), 2), (2, at(6, 13), 0), (2, at(7, 12), 3)] {
+            sqlx::query!(
+                "INSERT INTO user_tsumego_reviews (user_id, tsumego_id, review_date, grade) VALUES (?, ?, ?, ?)",
+                user_id,
+                tsumego_id,
+                review_date,
+                grade,
+            )
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+        
+        // 2024-01-08 is a Monday
+        send_due_digests_at(&state, at(8, 7)).await.unwrap();
+        send_due_digests_at(&state, at(8, 9)).await.unwrap();
+        send_due_digests_at(&state, at(8, 10)).await.unwrap();
+        send_due_digests_at(&state, at(9, 9)).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        
+        let sent = mailer.sent();
+        assert_eq!(1, sent.len());
+        
+        let body = &sent[0].body;
+        assert!(body.contains("Reviews done: 3"), "{body}");
+        assert!(body.contains("Retention: 67%"), "{body}");
+        assert!(body.contains("New tsumego learned: 2"), "{body}");
+        assert!(body.contains("Current streak: 2 days"), "{body}");
+        assert!(body.contains("unsubscribe/digest?token="), "{body}");
+    }
+}
@@ -0,0 +1,390 @@
+use authlogic::AppConfig;
+use chrono::TimeDelta;
+
+use crate::{
+    mailer::{Email, MailError},
+    model::time,
+    result::Result,
+    state::State,
+};
+
+/// The maximum number of emails sent in one run of the queue worker. Any
+/// others will be sent on the next run.
+const BATCH_SIZE: i64 = 20;
+
+/// The delay before retrying an email after its first failed attempt. The
+/// delay doubles after each further failure.
+const BASE_RETRY_DELAY_SECS: i64 = 60;
+
+/// The maximum delay before retrying an email.
+const MAX_RETRY_DELAY_SECS: i64 = 6 * 60 * 60;
+
+/// An email in the outbound queue, as shown to admins. The body isn't
+/// included, since it may contain a login link.
+#[derive(serde::Serialize)]
+pub struct QueuedMail {
+    pub id: i64,
+    pub recipient: String,
+    pub subject: String,
+    pub created: time::DateTime,
+    pub attempts: i64,
+    
+    /// When the email will next be attempted, or `None` if it failed too many
+    /// times and won't be retried.
+    #[serde(rename = "nextAttempt")]
+    pub next_attempt: Option<time::DateTime>,
+    
+    /// The error from the most recent failed attempt, if any.
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+}
+
+impl QueuedMail {
+    /// Fetches every email in the queue, oldest first.
+    pub async fn get_all(state: &State) -> Result<Vec<Self>> {
+        let queued = sqlx::query_as!(
+            Self,
+            "SELECT id, recipient, subject, created, attempts, next_attempt, last_error
+                FROM outbound_mail
+                ORDER BY id",
+        )
+            .fetch_all(&state.db)
+            .await?;
+        
+        Ok(queued)
+    }
+    
+    /// Schedules an email to be attempted again as soon as possible, with a
+    /// fresh count of attempts. Returns `false` if there is no such email.
+    pub async fn retry(state: &State, id: i64) -> Result<bool> {
+        let now = time::now();
+        let result = sqlx::query!(
+            "UPDATE outbound_mail SET attempts = 0, next_attempt = ? WHERE id = ?",
+            now,
+            id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Adds an email to the outbound queue. It will be sent by the queue worker,
+/// so a slow or broken mail relay doesn't hold up the request.
+/// 
+/// The email is stored in plain text, including any login or confirmation
+/// link in it, so anyone who can read the database can use the link until it
+/// expires. Emails are deleted once sent, and `delete_expired` deletes the
+/// rest once their links have expired.
+pub async fn enqueue(state: &State, email: Email) -> Result<()> {
+    let now = time::now();
+    sqlx::query!(
+        "INSERT INTO outbound_mail (recipient, subject, body, html_body, list_unsubscribe, created, next_attempt)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+        email.to,
+        email.subject,
+        email.body,
+        email.html_body,
+        email.list_unsubscribe,
+        now,
+        now,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    Ok(())
+}
+
+/// Attempts to send the emails in the queue which are due. Sent emails are
+/// deleted; failed emails are retried later, with an increasing delay, until
+/// they have been attempted too many times. This function will be called
+/// periodically.
+pub async fn send_due(state: &State) -> Result<()> {
+    let now = time::now();
+    let due = sqlx::query!(
+        "SELECT id, recipient, subject, body, html_body, list_unsubscribe, attempts FROM outbound_mail
+            WHERE next_attempt <= ?
+            ORDER BY next_attempt
+            LIMIT ?",
+        now,
+        BATCH_SIZE,
+    )
+        .fetch_all(&state.db)
+        .await?;
+    
+    for row in due {
+        // Claim the email before sending it, by scheduling the next attempt
+        // as if this one will fail. Then if this run stops partway, e.g. by
+        // timing out, the next run won't send the email again.
+        let attempts = row.attempts + 1;
+        let next_attempt = (attempts < state.cfg.mail_max_attempts)
+            .then(|| now + retry_delay(attempts));
+        let claimed = sqlx::query!(
+            "UPDATE outbound_mail SET attempts = ?, next_attempt = ?
+                WHERE id = ? AND next_attempt <= ?",
+            attempts,
+            next_attempt,
+            row.id,
+            now,
+        )
+            .execute(&state.db)
+            .await?
+            .rows_affected() > 0;
+        
+        if !claimed {
+            continue;
+        }
+        
+        let email = Email {
+            to: row.recipient,
+            subject: row.subject,
+            body: row.body,
+            html_body: row.html_body,
+            list_unsubscribe: row.list_unsubscribe,
+        };
+        
+        // The result is recorded by a separate task, which carries on if this
+        // run is cancelled, so a sent email is always deleted. A failure to
+        // record it shouldn't stop the rest of the batch from being sent.
+        let id = row.id;
+        let task_state = state.clone();
+        match actix_web::rt::spawn(async move { send_claimed(&task_state, id, email, attempts).await }).await {
+            Ok(Ok(())) => {},
+            Ok(Err(e)) => log::error!("Failed to record the result of sending email #{id}: {e:?}"),
+            Err(e) => log::error!("Failed to send email #{id}: {e:?}"),
+        }
+    }
+    
+    Ok(())
+}
+
+/// Sends an email which has been claimed by `send_due`, and records the
+/// result.
+async fn send_claimed(state: &State, id: i64, email: Email, attempts: i64) -> Result<()> {
+    // Mailers may block, e.g. while talking to the SMTP relay. A mailer which
+    // panics is treated like one which failed, so the email is retried.
+    let mailer_state = state.clone();
+    let result = actix_web::rt::task::spawn_blocking(move || mailer_state.mailer.send(&email))
+        .await
+        .unwrap_or_else(|e| Err(MailError::Io(std::io::Error::other(e))));
+    
+    match result {
+        Ok(()) => {
+            sqlx::query!("DELETE FROM outbound_mail WHERE id = ?", id)
+                .execute(&state.db)
+                .await?;
+        },
+        Err(err) => {
+            let last_error = format!("{err:?}");
+            if attempts < state.cfg.mail_max_attempts {
+                log::warn!("Failed to send email #{id} (attempt {attempts}): {last_error}");
+            } else {
+                log::error!("Failed to send email #{id} after {attempts} attempts; giving up: {last_error}");
+            }
+            
+            sqlx::query!(
+                "UPDATE outbound_mail SET last_error = ? WHERE id = ?",
+                last_error,
+                id,
+            )
+                .execute(&state.db)
+                .await?;
+        },
+    }
+    
+    Ok(())
+}
+
+/// Deletes emails which are older than the expiry time of the links they may
+/// contain, whether or not they are still being retried. Sending them would
+/// be pointless, and keeping them would keep their links in the database.
+/// Emails which failed too many times are kept until then, so that admins
+/// can see and retry them. This function will be called periodically.
+pub async fn delete_expired(state: &State) -> Result<()> {
+    let expiry_hours = state.challenge_expire_after_hours() as f64;
+    let cutoff = time::add_days(time::now(), -expiry_hours / 24.0);
+    
+    let result = sqlx::query!("DELETE FROM outbound_mail WHERE created <= ?", cutoff)
+        .execute(&state.db)
+        .await?;
+    
+    if result.rows_affected() > 0 {
+        log::info!("Deleted {} expired emails from the outbound queue", result.rows_affected());
+    }
+    
+    Ok(())
+}
+
+/// Computes the delay before the next attempt to send an email, after the
+/// given number of failed attempts.
+fn retry_delay(attempts: i64) -> TimeDelta {
+    // Avoid overflow; the maximum delay is reached long before this
+    let factor = 1i64 << (attempts - 1).clamp(0, 20);
+    TimeDelta::seconds((BASE_RETRY_DELAY_SECS * factor).min(MAX_RETRY_DELAY_SECS))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+    
+    use actix_web::rt::time::{sleep, timeout};
+    
+    use super::{delete_expired, retry_delay, send_due};
+    use crate::{
+        mailer::{Email, MailError, Mailer, MemoryMailer},
+        model::time,
+        state,
+    };
+    
+    /// A mailer which always fails, like an unreachable relay.
+    struct BrokenMailer;
+    
+    impl Mailer for BrokenMailer {
+        fn send(&self, _email: &Email) -> Result<(), MailError> {
+            Err(MailError::Io(std::io::Error::other("connection refused")))
+        }
+    }
+    
+    /// A mailer which panics, like one with a bug.
+    struct PanickingMailer;
+    
+    impl Mailer for PanickingMailer {
+        fn send(&self, _email: &Email) -> Result<(), MailError> {
+            panic!("Mailer bug");
+        }
+    }
+    
+    /// A mailer which takes a while to send each email, like a slow relay.
+    struct SlowMailer(MemoryMailer);
+    
+    impl Mailer for SlowMailer {
+        fn send(&self, email: &Email) -> Result<(), MailError> {
+            std::thread::sleep(Duration::from_millis(200));
+            self.0.send(email)
+        }
+    }
+    
+    #[test]
+    fn retry_delay_doubles_up_to_maximum() {
+        let delays: Vec<i64> = (1..=11)
+            .map(|attempts| retry_delay(attempts).num_minutes())
+            .collect();
+        
+        assert_eq!(vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 360, 360], delays);
+    }
+    
+    fn example_email() -> Email {
+        Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hello, Alice".to_string(),
+            html_body: None,
+            list_unsubscribe: None,
+        }
+    }
+    
+    #[actix_web::test]
+    async fn failed_mail_is_kept_for_retry() {
+        let state = state::for_test(Box::new(BrokenMailer)).await;
+        super::enqueue(&state, example_email())
+            .await
+            .unwrap();
+        
+        send_due(&state)
+            .await
+            .unwrap();
+        
+        let queued = super::QueuedMail::get_all(&state)
+            .await
+            .unwrap();
+        assert_eq!(1, queued.len());
+        assert_eq!(1, queued[0].attempts);
+        assert!(queued[0].next_attempt.is_some());
+        assert!(queued[0].last_error.as_deref().is_some_and(|e| e.contains("connection refused")));
+        
+        // The email isn't due again yet
+        send_due(&state)
+            .await
+            .unwrap();
+        let queued = super::QueuedMail::get_all(&state)
+            .await
+            .unwrap();
+        assert_eq!(1, queued[0].attempts);
+    }
+    
+    #[actix_web::test]
+    async fn expired_mail_is_deleted() {
+        let state = state::for_test(Box::new(BrokenMailer)).await;
+        for _ in 0..2 {
+            super::enqueue(&state, example_email())
+                .await
+                .unwrap();
+        }
+        
+        // One email is older than any link it could contain
+        let long_ago = time::add_days(time::now(), -30.0);
+        sqlx::query!("UPDATE outbound_mail SET created = ? WHERE id = 1", long_ago)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        delete_expired(&state)
+            .await
+            .unwrap();
+        
+        let queued = super::QueuedMail::get_all(&state)
+            .await
+            .unwrap();
+        assert_eq!(1, queued.len());
+        assert_eq!(2, queued[0].id);
+    }
+    
+    #[actix_web::test]
+    async fn panicking_mailer_is_a_failure() {
+        let state = state::for_test(Box::new(PanickingMailer)).await;
+        for _ in 0..2 {
+            super::enqueue(&state, example_email())
+                .await
+                .unwrap();
+        }
+        
+        send_due(&state)
+            .await
+            .unwrap();
+        
+        // Both emails were attempted, and will be retried later
+        let queued = super::QueuedMail::get_all(&state)
+            .await
+            .unwrap();
+        assert_eq!(2, queued.len());
+        for mail in queued {
+            assert_eq!(1, mail.attempts);
+            assert!(mail.next_attempt.is_some_and(|t| t > time::now()));
+            assert!(mail.last_error.is_some_and(|e| e.contains("Mailer bug")));
+        }
+    }
+    
+    #[actix_web::test]
+    async fn cancelled_run_does_not_send_twice() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(SlowMailer(mailer.clone()))).await;
+        super::enqueue(&state, example_email())
+            .await
+            .unwrap();
+        
+        // The run times out while the email is being sent
+        assert!(timeout(Duration::from_millis(50), send_due(&state)).await.is_err());
+        send_due(&state)
+            .await
+            .unwrap();
+        
+        // The email is still sent and deleted after the run is cancelled
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !super::QueuedMail::get_all(&state).await.unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "Email should be deleted once it is sent");
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(1, mailer.sent().len());
+    }
+}
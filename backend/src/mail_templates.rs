@@ -0,0 +1,119 @@
+use std::path::Path;
+
+use minijinja::{Environment, Value};
+
+/// The directory which email templates are loaded from.
+const TEMPLATE_DIR: &str = "templates/mail";
+
+/// The emails which this application sends. Each must have a `.txt` and a
+/// `.html` template, which usually extend `layout.txt` and `layout.html`.
+const EMAIL_NAMES: &[&str] = &[
+    "account_suspended",
+    "account_unsuspended",
+    "change_email",
+    "daily_reminder",
+    "delete_account",
+    "email_changed",
+    "log_in",
+    "password_changed",
+    "reset_password",
+    "user_registered",
+    "verify_account",
+    "weekly_digest",
+];
+
+/// The templates for emails, which are compiled once when the server starts.
+/// HTML templates are autoescaped; text templates are not.
+pub struct MailTemplates {
+    env: Environment<'static>,
+}
+
+impl MailTemplates {
+    /// Loads and compiles every template in the template directory. Returns
+    /// an error if a template is invalid, or if any email is missing one of
+    /// its templates.
+    pub fn load() -> Result<Self, minijinja::Error> {
+        Self::load_from(Path::new(TEMPLATE_DIR))
+    }
+    
+    fn load_from(dir: &Path) -> Result<Self, minijinja::Error> {
+        let mut env = Environment::new();
+        // Tags on their own lines shouldn't leave blank lines in text emails
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| template_error(format!("Failed to read {}: {e}", dir.display())))?;
+        
+        for entry in entries {
+            let path = entry
+                .map_err(|e| template_error(e.to_string()))?
+                .path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let source = std::fs::read_to_string(&path)
+                .map_err(|e| template_error(format!("Failed to read {}: {e}", path.display())))?;
+            env.add_template_owned(name.to_string(), source)?;
+        }
+        
+        for name in EMAIL_NAMES {
+            env.get_template(&format!("{name}.txt"))?;
+            env.get_template(&format!("{name}.html"))?;
+        }
+        
+        Ok(Self {env})
+    }
+    
+    /// Renders the text and HTML bodies of an email.
+    pub fn render(&self, name: &str, context: &Value) -> Result<(String, String), minijinja::Error> {
+        let text = self.env.get_template(&format!("{name}.txt"))?
+            .render(context)?;
+        let html = self.env.get_template(&format!("{name}.html"))?
+            .render(context)?;
+        Ok((text, html))
+    }
+}
+
+fn template_error(detail: String) -> minijinja::Error {
+    minijinja::Error::new(minijinja::ErrorKind::TemplateNotFound, detail)
+}
+
+#[cfg(test)]
+mod test {
+    use minijinja::context;
+    
+    use super::MailTemplates;
+    
+    #[test]
+    fn all_templates_load() {
+        assert!(MailTemplates::load().is_ok());
+    }
+    
+    #[test]
+    fn html_is_escaped_but_text_is_not() {
+        let templates = MailTemplates::load().unwrap();
+        let context = context! {
+            display_name => "<Alice & Bob>",
+            base_url => "http://127.0.0.1:8000/",
+            reason => "Spam",
+        };
+        
+        let (text, html) = templates.render("account_suspended", &context)
+            .unwrap();
+        assert!(text.starts_with("Hi <Alice & Bob>,\n\n"));
+        assert!(html.contains("Hi &lt;Alice &amp; Bob&gt;,"));
+        assert!(html.contains("<blockquote>Spam</blockquote>"));
+    }
+    
+    #[test]
+    fn missing_template_fails_to_load() {
+        let dir = std::env::temp_dir().join(format!("tsumego-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("log_in.txt"), "Hi").unwrap();
+        
+        let result = MailTemplates::load_from(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+}
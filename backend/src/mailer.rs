@@ -0,0 +1,237 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use lettre::{
+    message::{
+        header::{ContentType, HeaderName, HeaderValue},
+        Mailbox,
+        MultiPart,
+    },
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+        PoolConfig,
+        SmtpTransport,
+    },
+    Message,
+    Transport,
+};
+use rand::Rng;
+
+use crate::config::{Config, MailTransport, SmtpTls};
+
+/// An email which is ready to be sent.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    /// An alternative HTML body, for mail clients which can show it.
+    pub html_body: Option<String>,
+    /// The URL which unsubscribes the recipient, if this email is from a
+    /// mailing list. Mail clients can offer a one-click unsubscribe button
+    /// which sends a POST request to it, as described in RFC 8058.
+    pub list_unsubscribe: Option<String>,
+}
+
+/// An error which might occur when trying to send an email.
+#[derive(Debug)]
+pub enum MailError {
+    Address(lettre::address::AddressError),
+    Compose(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    Io(std::io::Error),
+}
+
+/// A way of delivering emails. The implementation is chosen by the
+/// `MAIL_TRANSPORT` config parameter.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+    
+    /// Checks that mail can be delivered, e.g. that the SMTP relay accepts
+    /// connections. This is called once, when the server starts.
+    fn check(&self) -> Result<(), MailError> {
+        Ok(())
+    }
+}
+
+/// Builds the mailer chosen by the config.
+pub fn from_config(cfg: &Config) -> Result<Box<dyn Mailer>, MailError> {
+    let mailer: Box<dyn Mailer> = match cfg.mail_transport {
+        MailTransport::Smtp => Box::new(SmtpMailer::new(cfg)?),
+        MailTransport::File => Box::new(FileMailer::new(cfg)?),
+        MailTransport::Log => Box::new(LogMailer),
+        MailTransport::Memory => Box::new(MemoryMailer::default()),
+    };
+    Ok(mailer)
+}
+
+/// Sends mail through the SMTP relay given in the config. Connections are
+/// pooled, and are only opened when mail is sent.
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn new(cfg: &Config) -> Result<Self, MailError> {
+        let tls_parameters = || TlsParameters::new(cfg.smtp_host.to_string())
+            .map_err(MailError::Smtp);
+        
+        let tls = match cfg.smtp_tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls => Tls::Required(tls_parameters()?),
+            SmtpTls::Tls => Tls::Wrapper(tls_parameters()?),
+        };
+        
+        let mut builder = SmtpTransport::builder_dangerous(cfg.smtp_host.as_ref())
+            .port(cfg.smtp_port)
+            .tls(tls)
+            .pool_config(PoolConfig::new().max_size(cfg.smtp_pool_size));
+        
+        if let (Some(username), Some(password)) = (&cfg.smtp_username, &cfg.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.to_string(), password.to_string()));
+        }
+        
+        Ok(Self {
+            from: parse_from_address(cfg)?,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(&message)
+            .map_err(MailError::Smtp)?;
+        Ok(())
+    }
+    
+    fn check(&self) -> Result<(), MailError> {
+        self.transport.test_connection()
+            .map_err(MailError::Smtp)?
+            .then_some(())
+            .ok_or_else(|| MailError::Io(std::io::Error::other("SMTP relay did not respond")))
+    }
+}
+
+/// Writes each email to a file in a maildir, so that the mail can be read by
+/// a mail client, or checked by a script. Each message is written to `tmp`
+/// first and then moved to `new`, so readers never see a partial message.
+pub struct FileMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(cfg: &Config) -> Result<Self, MailError> {
+        let dir = PathBuf::from(cfg.mail_dir.as_deref().unwrap_or("mail"));
+        for subdir in ["tmp", "new", "cur"] {
+            std::fs::create_dir_all(dir.join(subdir))
+                .map_err(MailError::Io)?;
+        }
+        
+        Ok(Self {
+            from: parse_from_address(cfg)?,
+            dir,
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        
+        let timestamp = chrono::Utc::now().timestamp();
+        let unique: u64 = rand::thread_rng().gen();
+        let file_name = format!("{timestamp}.{unique:016x}.tsumego");
+        
+        let tmp_path = self.dir.join("tmp").join(&file_name);
+        std::fs::write(&tmp_path, message.formatted())
+            .and_then(|_| std::fs::rename(&tmp_path, self.dir.join("new").join(&file_name)))
+            .map_err(MailError::Io)
+    }
+}
+
+/// Doesn't send mail, but logs each message which would be sent.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        log::info!("Would send mail to {}:\nSubject: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+/// Doesn't send mail, but keeps each message in memory, so that tests can
+/// check what would be sent. Clones share the same list of messages.
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    /// Gets all of the messages which have been sent so far.
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.lock().clone()
+    }
+    
+    /// Finds the first link in the most recent message sent to the address.
+    #[cfg(test)]
+    pub fn last_link_to(&self, to: &str) -> Option<String> {
+        let sent = self.lock();
+        let email = sent.iter().rev().find(|e| e.to == to)?;
+        
+        email.body.split_whitespace()
+            .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+            .map(str::to_string)
+    }
+    
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Email>> {
+        self.sent.lock()
+            .expect("Mailer lock should not be poisoned")
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.lock().push(email.clone());
+        Ok(())
+    }
+}
+
+fn parse_from_address(cfg: &Config) -> Result<Mailbox, MailError> {
+    cfg.email_from
+        .parse()
+        .map_err(MailError::Address)
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, MailError> {
+    let to: Mailbox = email.to
+        .parse()
+        .map_err(MailError::Address)?;
+    
+    let mut builder = Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject);
+    
+    if let Some(url) = &email.list_unsubscribe {
+        builder = builder
+            .raw_header(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe"), format!("<{url}>")))
+            .raw_header(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe-Post"), "List-Unsubscribe=One-Click".to_string()));
+    }
+    
+    match &email.html_body {
+        Some(html_body) => builder
+            .multipart(MultiPart::alternative_plain_html(email.body.clone(), html_body.clone())),
+        None => builder
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body.clone()),
+    }
+        .map_err(MailError::Compose)
+}
@@ -1,8 +1,16 @@
 mod auth;
+mod backup;
 mod config;
+mod digest;
+mod mail_queue;
+mod mail_templates;
+mod mailer;
+mod migrations;
 mod middleware;
 mod model;
 mod periodic_jobs;
+mod rate_limit;
+mod reminders;
 mod result;
 mod routes;
 mod state;
@@ -21,6 +29,35 @@ async fn main() -> std::io::Result<()> {
         .format_module_path(false)
         .init();
     
+    // Commands for administering the server, which run instead of it
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        None => {},
+        Some("restore") if args.len() == 3 => {
+            let cfg = config::Config::get_from_env();
+            backup::restore(&cfg, std::path::Path::new(&args[2]))
+                .await
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to restore database: {err:?}");
+                    std::process::exit(1);
+                });
+            println!("Restored database from {}", args[2]);
+            return Ok(());
+        },
+        Some(_) => {
+            eprintln!("Usage: {} [restore <backup file>]", args[0]);
+            std::process::exit(1);
+        },
+    }
+    
+    // Hold a lock on the database while the server runs, so that a backup
+    // can't be restored underneath it
+    let _database_lock = backup::lock_for_server(&config::Config::get_from_env())
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to lock database: {err:?}");
+            std::process::exit(1);
+        });
+    
     // Initialise application state
     let state = state::from_env().await;
     
@@ -38,7 +75,11 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(state.clone())
             .configure(routes::declare_routes)
+            // Session tracking applies after we authenticate the user
+            .wrap(middleware::from_fn(crate::middleware::track_sessions_middleware))
             .wrap(middleware::from_fn(authlogic::middleware::<state::State>))
+            // Removing the session cookie applies after it may be issued
+            .wrap(middleware::from_fn(crate::middleware::clear_session_cookie_middleware))
             // CSRF protection applies before we authenticate the user
             .wrap(middleware::from_fn(crate::middleware::csrf_middleware))
             // Logging is the outer-most middleware, so the log can see all
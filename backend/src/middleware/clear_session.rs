@@ -0,0 +1,40 @@
+use actix_web::{
+    body::MessageBody,
+    cookie::Cookie,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    Error,
+    HttpMessage,
+};
+
+use crate::state::State;
+
+/// A marker which a route handler inserts into the request's extensions, to
+/// have the session cookie removed from the client. This is needed when the
+/// user's account is deleted, since completing the challenge issues them a
+/// new session cookie.
+pub struct ClearSessionCookie;
+
+/// A middleware which removes the session cookie from the client, when the
+/// route handler asks for it with `ClearSessionCookie`. This must be wrapped
+/// outside the authentication middleware, so that it can replace the cookie
+/// which that middleware issues.
+pub async fn clear_session_cookie_middleware(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let mut response = next.call(request).await?;
+    
+    if response.request().extensions().contains::<ClearSessionCookie>() {
+        let state: State = response.request()
+            .app_data::<State>()
+            .expect("State should be available from app data")
+            .clone();
+        let cookie_name = state.cfg.session_token_cookie_name.to_string();
+        
+        response.response_mut().del_cookie(&cookie_name);
+        response.response_mut().add_removal_cookie(&Cookie::new(cookie_name, ""))?;
+    }
+    
+    Ok(response)
+}
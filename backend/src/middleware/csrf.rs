@@ -30,7 +30,7 @@ pub async fn csrf_middleware(
     // "Safe" methods are GET, HEAD, OPTIONS and TRACE. Other methods are
     // allowed to change application state, so we want to ensure the request
     // isn't cross-site.
-    if !request.method().is_safe() && is_bad_request(&request) {
+    if !request.method().is_safe() && !is_api_token_request(&request) && !is_unsubscribe_request(&request) && is_bad_request(&request) {
         return Err(AppError::BAD_REQUEST.into());
     }
     
@@ -38,6 +38,30 @@ pub async fn csrf_middleware(
     next.call(request).await
 }
 
+/// Determines whether a request is authenticated by an API token, and so
+/// doesn't need this protection; browsers don't add an `Authorization` header
+/// to cross-site requests. Only a bearer token counts, since other kinds of
+/// `Authorization` header aren't accepted instead of a session. A request
+/// which also has a session cookie is still checked, since routes which don't
+/// accept API tokens would authenticate it by the cookie.
+fn is_api_token_request(request: &ServiceRequest) -> bool {
+    let state: &State = request
+        .app_data::<State>()
+        .expect("State should be available from app data");
+    
+    crate::routes::bearer_token(request.request()).is_some()
+        && request.cookie(&state.cfg.session_token_cookie_name).is_none()
+}
+
+/// Determines whether a request is to unsubscribe from a mailing list. These
+/// requests are authenticated by a signed token in the URL rather than a
+/// session, so a cross-site request can't be made on the user's behalf. They
+/// may come from a mail client, which sends no Referer header.
+fn is_unsubscribe_request(request: &ServiceRequest) -> bool {
+    // This must match the `unsubscribe` route URL
+    request.path().starts_with("/unsubscribe/")
+}
+
 fn is_bad_request(request: &ServiceRequest) -> bool {
     let state: &State = request
         .app_data::<State>()
@@ -1,3 +1,7 @@
+mod clear_session;
 mod csrf;
+mod sessions;
 
+pub use clear_session::{clear_session_cookie_middleware, ClearSessionCookie};
 pub use csrf::csrf_middleware;
+pub use sessions::track_sessions_middleware;
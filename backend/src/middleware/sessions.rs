@@ -0,0 +1,43 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header,
+    middleware::Next,
+    Error,
+};
+
+use crate::{
+    auth::MaybeAuth,
+    model::Session,
+    state::State,
+};
+
+/// A middleware which records when each session was last used, and by which
+/// client, so that users can review their active sessions. This must be
+/// wrapped inside the authentication middleware, so that the session is
+/// known.
+pub async fn track_sessions_middleware(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let auth = authlogic::maybe_auth_from_request::<State>(&request);
+    
+    if let MaybeAuth::Authenticated(auth) = auth {
+        let state: &State = request
+            .app_data::<State>()
+            .expect("State should be available from app data");
+        
+        let user_agent = request.headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok());
+        let ip_address = state.cfg.client_ip(request.request());
+        
+        // Failing to record this shouldn't prevent the request from being
+        // handled
+        if let Err(e) = Session::touch(state, auth.session_id, user_agent, ip_address.as_deref()).await {
+            log::error!("Failed to update session #{}: {e:?}", auth.session_id);
+        }
+    }
+    
+    next.call(request).await
+}
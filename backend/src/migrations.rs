@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migrator},
+    sqlite::SqlitePool,
+};
+
+/// The database migrations in `migrations/`, which are embedded in the
+/// server when it is compiled.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// A reason the server can't use the database's schema.
+#[derive(Debug)]
+pub enum SchemaError {
+    /// The database has migrations which this server doesn't know, so it was
+    /// probably migrated by a newer version of the server.
+    NewerThanServer(Vec<i64>),
+    /// The database is missing migrations, and applying them automatically is
+    /// disabled.
+    Pending(Vec<i64>),
+    Migrate(MigrateError),
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::NewerThanServer(versions) => write!(f, "the database has migrations {versions:?} which this server doesn't know; it may have been migrated by a newer version"),
+            SchemaError::Pending(versions) => write!(f, "migrations {versions:?} are pending; set DATABASE_AUTO_MIGRATE=true or run `sqlx migrate run`"),
+            SchemaError::Migrate(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<MigrateError> for SchemaError {
+    fn from(err: MigrateError) -> Self {
+        SchemaError::Migrate(err)
+    }
+}
+
+impl From<sqlx::Error> for SchemaError {
+    fn from(err: sqlx::Error) -> Self {
+        SchemaError::Migrate(err.into())
+    }
+}
+
+/// Checks that the database's schema matches the embedded migrations. If
+/// `auto_apply` is true, any pending migrations are applied; otherwise they
+/// are an error.
+pub async fn prepare(db: &SqlitePool, auto_apply: bool) -> Result<(), SchemaError> {
+    let mut conn = db.acquire()
+        .await?;
+    conn.ensure_migrations_table()
+        .await?;
+    if let Some(version) = conn.dirty_version().await? {
+        return Err(MigrateError::Dirty(version).into());
+    }
+    let applied = conn.list_applied_migrations()
+        .await?;
+    // The pool may only have one connection, which the migrator needs
+    drop(conn);
+    
+    let known: HashSet<i64> = up_migrations()
+        .map(|m| m.version)
+        .collect();
+    let newer: Vec<i64> = applied.iter()
+        .map(|m| m.version)
+        .filter(|version| !known.contains(version))
+        .collect();
+    if !newer.is_empty() {
+        return Err(SchemaError::NewerThanServer(newer));
+    }
+    
+    for migration in up_migrations() {
+        let modified = applied.iter()
+            .any(|m| m.version == migration.version && m.checksum != migration.checksum);
+        if modified {
+            return Err(MigrateError::VersionMismatch(migration.version).into());
+        }
+    }
+    
+    let pending: Vec<i64> = up_migrations()
+        .map(|m| m.version)
+        .filter(|version| applied.iter().all(|m| m.version != *version))
+        .collect();
+    if pending.is_empty() {
+        return Ok(());
+    } else if !auto_apply {
+        return Err(SchemaError::Pending(pending));
+    }
+    
+    MIGRATOR.run(db)
+        .await?;
+    log::info!("Applied database migrations {pending:?}");
+    
+    Ok(())
+}
+
+fn up_migrations() -> impl Iterator<Item = &'static sqlx::migrate::Migration> {
+    MIGRATOR.iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+    
+    use super::{prepare, SchemaError};
+    
+    async fn empty_db() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("Failed to open an in-memory database")
+    }
+    
+    #[actix_web::test]
+    async fn migrations_are_only_applied_when_enabled() {
+        let db = empty_db().await;
+        assert!(matches!(prepare(&db, false).await, Err(SchemaError::Pending(_))));
+        
+        prepare(&db, true).await.unwrap();
+        prepare(&db, false).await.unwrap();
+    }
+    
+    #[actix_web::test]
+    async fn newer_schema_is_refused() {
+        let db = empty_db().await;
+        prepare(&db, true).await.unwrap();
+        
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+                VALUES (99990101, 'from the future', 1, x'00', 0)",
+        )
+            .execute(&db)
+            .await
+            .unwrap();
+        
+        let result = prepare(&db, true).await;
+        assert!(matches!(result, Err(SchemaError::NewerThanServer(v)) if v == [99990101]));
+    }
+}
@@ -0,0 +1,460 @@
+use sqlx::SqliteConnection;
+
+use crate::{
+    auth::{ApiScope, ApiToken},
+    model::{time, User, UserPreferences},
+    result::Result,
+    state::State,
+};
+
+/// The version of the export format. This should be incremented whenever the
+/// format changes in a way which older importers can't read.
+pub const EXPORT_FORMAT_VERSION: i64 = 1;
+
+/// The documentation of the export format, which is included in each export
+/// archive so that the archive can be understood without this application.
+const FORMAT_DOCUMENTATION: &str = include_str!("../../docs/export_format.md");
+
+/// The size of a block in a tar archive. Each file's header and contents are
+/// padded to a whole number of blocks.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// A tar archive ends with two empty blocks.
+pub const TAR_END: [u8; 2 * TAR_BLOCK_SIZE] = [0; 2 * TAR_BLOCK_SIZE];
+
+/// Everything stored about a user's study and account, in a portable format.
+/// Tsumego are identified by name instead of by id, since ids are specific to
+/// one database; this allows an export to be imported into another instance
+/// of the application, or read by another tool.
+/// 
+/// The format is documented in `docs/export_format.md`. Only the profile,
+/// stats and reviews are needed to import an export; the other details are
+/// informational.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UserExport {
+    pub version: i64,
+    #[serde(rename = "exportedAt")]
+    pub exported_at: time::DateTime,
+    pub profile: ExportedProfile,
+    #[serde(default)]
+    pub preferences: UserPreferences,
+    #[serde(rename = "apiTokens", default)]
+    pub api_tokens: Vec<ExportedApiToken>,
+    #[serde(default)]
+    pub sessions: Vec<ExportedSession>,
+    pub stats: Vec<ExportedStats>,
+    pub reviews: Vec<ExportedReview>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedProfile {
+    pub email: String,
+    #[serde(rename = "displayName")]
+    pub display_name: String,
+}
+
+/// A user's SRS state for one tsumego; see `UserTsumegoStats`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedStats {
+    pub tsumego: String,
+    #[serde(rename = "lastReviewDate")]
+    pub last_review_date: time::DateTime,
+    #[serde(rename = "reviewDue")]
+    pub review_due: Option<time::DateTime>,
+    #[serde(rename = "numReviews")]
+    pub num_reviews: i64,
+    #[serde(rename = "streakLength")]
+    pub streak_length: i64,
+    pub interval: f64,
+    #[serde(rename = "eFactor")]
+    pub e_factor: f64,
+}
+
+/// The details of one of the user's API tokens, but not the token itself;
+/// see `ApiToken`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedApiToken {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub created: time::DateTime,
+    #[serde(rename = "lastUsed")]
+    pub last_used: Option<time::DateTime>,
+}
+
+/// The details of one of the user's unexpired sessions, but not the session
+/// token; see `Session`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedSession {
+    pub created: Option<time::DateTime>,
+    #[serde(rename = "lastSeen")]
+    pub last_seen: Option<time::DateTime>,
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+    pub expires: time::DateTime,
+}
+
+/// One review in a user's study history; see `ReviewDetails`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedReview {
+    pub tsumego: String,
+    #[serde(rename = "reviewDate")]
+    pub review_date: time::DateTime,
+    pub grade: i64,
+    #[serde(rename = "solveTimeMs")]
+    pub solve_time_ms: Option<i64>,
+    #[serde(rename = "numWrongMoves")]
+    pub num_wrong_moves: Option<i64>,
+    #[serde(rename = "usedHint")]
+    pub used_hint: bool,
+    pub moves: Option<Vec<String>>,
+}
+
+/// The outcome of importing a `UserExport`.
+#[derive(serde::Serialize)]
+pub struct ImportOutcome {
+    #[serde(rename = "numStats")]
+    pub num_stats: i64,
+    #[serde(rename = "numReviews")]
+    pub num_reviews: i64,
+    /// The number of stats and reviews which were skipped, because they refer
+    /// to tsumego which aren't in this database, or are already recorded. An
+    /// Anki import also skips review log entries which aren't reviews.
+    #[serde(rename = "numSkipped")]
+    pub num_skipped: i64,
+}
+
+impl UserExport {
+    /// Collects everything stored about this user's study and account. It is
+    /// all read in one transaction, so the export is a consistent snapshot.
+    pub async fn get_for_user(state: &State, user: &User) -> Result<Self> {
+        let now = time::now();
+        let mut tx = state.db.begin().await?;
+        
+        let preferences = UserPreferences::get_for_user(&mut tx, user.id)
+            .await?;
+        
+        let api_tokens = ApiToken::get_all_for_user(&mut tx, user.id)
+            .await?
+            .into_iter()
+            .map(|t| ExportedApiToken {
+                name: t.name,
+                scopes: t.scopes,
+                created: t.created,
+                last_used: t.last_used,
+            })
+            .collect();
+        
+        let sessions = sqlx::query_as!(
+            ExportedSession,
+            "SELECT created, last_seen, user_agent, ip_address, expires
+                FROM sessions
+                WHERE user_id = ? AND expires > ?
+                ORDER BY created",
+            user.id,
+            now,
+        )
+            .fetch_all(&mut *tx)
+            .await?;
+        
+        let stats = Self::get_stats(&mut tx, user.id)
+            .await?;
+        let reviews = Self::get_reviews(&mut tx, user.id)
+            .await?;
+        
+        tx.commit().await?;
+        
+        Ok(Self {
+            version: EXPORT_FORMAT_VERSION,
+            exported_at: now,
+            profile: ExportedProfile {
+                email: user.email.clone(),
+                display_name: user.display_name.clone(),
+            },
+            preferences,
+            api_tokens,
+            sessions,
+            stats,
+            reviews,
+        })
+    }
+    
+    /// Collects the user's SRS state for each tsumego they have studied.
+    pub async fn get_stats(conn: &mut SqliteConnection, user_id: i64) -> Result<Vec<ExportedStats>> {
+        let stats = sqlx::query_as!(
+            ExportedStats,
+            "SELECT tsumego.name AS tsumego,
+                    s.last_review_date, s.review_due,
+                    s.num_reviews, s.streak_length, s.interval, s.e_factor
+                FROM user_tsumego_stats s
+                INNER JOIN tsumego ON s.tsumego_id = tsumego.id
+                WHERE s.user_id = ?
+                ORDER BY tsumego.name",
+            user_id,
+        )
+            .fetch_all(&mut *conn)
+            .await?;
+        
+        Ok(stats)
+    }
+    
+    /// Collects the user's whole review history, oldest first.
+    pub async fn get_reviews(conn: &mut SqliteConnection, user_id: i64) -> Result<Vec<ExportedReview>> {
+        let reviews = sqlx::query!(
+            "SELECT tsumego.name AS tsumego,
+                    r.review_date, r.grade,
+                    r.solve_time_ms, r.num_wrong_moves, r.used_hint, r.moves
+                FROM user_tsumego_reviews r
+                INNER JOIN tsumego ON r.tsumego_id = tsumego.id
+                WHERE r.user_id = ?
+                ORDER BY r.review_date",
+            user_id,
+        )
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|r| ExportedReview {
+                tsumego: r.tsumego,
+                review_date: r.review_date,
+                grade: r.grade,
+                solve_time_ms: r.solve_time_ms,
+                num_wrong_moves: r.num_wrong_moves,
+                used_hint: r.used_hint,
+                moves: r.moves.and_then(|m| serde_json::from_str(&m).ok()),
+            })
+            .collect();
+        
+        Ok(reviews)
+    }
+    
+    /// Determines whether this export can be imported. The export might have
+    /// been edited by hand, or produced by another tool, so it isn't trusted.
+    pub fn is_valid(&self) -> bool {
+        (1..=EXPORT_FORMAT_VERSION).contains(&self.version)
+            && self.reviews.iter().all(|r| (0..=3).contains(&r.grade))
+            && self.stats.iter().all(|s| s.interval > 0.0 && s.e_factor > 0.0)
+    }
+    
+    /// Loads an export into this user's study history. Stats replace the
+    /// user's existing stats for the same tsumego, and reviews are added to
+    /// their history, unless a review of the same tsumego at the same time is
+    /// already recorded. The profile, preferences, API tokens and sessions are
+    /// not imported.
+    pub async fn import_for_user(&self, state: &State, user: &User) -> Result<ImportOutcome> {
+        let mut outcome = ImportOutcome {
+            num_stats: 0,
+            num_reviews: 0,
+            num_skipped: 0,
+        };
+        
+        let mut tx = state.db.begin().await?;
+        
+        for s in &self.stats {
+            let result = sqlx::query!(
+                "INSERT OR REPLACE INTO user_tsumego_stats
+                    (user_id, tsumego_id, last_review_date, review_due, num_reviews, streak_length, interval, e_factor)
+                    SELECT ?, id, ?, ?, ?, ?, ?, ? FROM tsumego WHERE name = ?",
+                user.id,
+                s.last_review_date,
+                s.review_due,
+                s.num_reviews,
+                s.streak_length,
+                s.interval,
+                s.e_factor,
+                s.tsumego,
+            )
+                .execute(&mut *tx)
+                .await?;
+            
+            if result.rows_affected() > 0 {
+                outcome.num_stats += 1;
+            } else {
+                outcome.num_skipped += 1;
+            }
+        }
+        
+        for r in &self.reviews {
+            let known = sqlx::query_scalar!(
+                "SELECT COUNT(1) FROM tsumego WHERE name = ?",
+                r.tsumego,
+            )
+                .fetch_one(&mut *tx)
+                .await?;
+            
+            if known == 0 {
+                outcome.num_skipped += 1;
+                continue;
+            }
+            
+            let moves = r.moves.as_ref()
+                .map(|moves| serde_json::Value::from(moves.as_slice()).to_string());
+            
+            let result = sqlx::query!(
+                "INSERT INTO user_tsumego_reviews
+                    (user_id, tsumego_id, review_date, grade, solve_time_ms, num_wrong_moves, used_hint, moves)
+                    SELECT ?, tsumego.id, ?, ?, ?, ?, ?, ? FROM tsumego
+                        WHERE tsumego.name = ?
+                        AND NOT EXISTS (
+                            SELECT 1 FROM user_tsumego_reviews r
+                                WHERE r.user_id = ? AND r.tsumego_id = tsumego.id AND r.review_date = ?
+                        )",
+                user.id,
+                r.review_date,
+                r.grade,
+                r.solve_time_ms,
+                r.num_wrong_moves,
+                r.used_hint,
+                moves,
+                r.tsumego,
+                user.id,
+                r.review_date,
+            )
+                .execute(&mut *tx)
+                .await?;
+            
+            outcome.num_reviews += result.rows_affected() as i64;
+        }
+        
+        tx.commit().await?;
+        
+        Ok(outcome)
+    }
+}
+
+/// Writes stats as CSV, with a header row.
+pub fn stats_csv(stats: &[ExportedStats]) -> String {
+    let mut csv = String::from("tsumego,lastReviewDate,reviewDue,numReviews,streakLength,interval,eFactor\n");
+    for s in stats {
+        let review_due = s.review_due.map(|d| d.to_string()).unwrap_or_default();
+        write_csv_row(&mut csv, &[
+            &s.tsumego,
+            &s.last_review_date.to_string(),
+            &review_due,
+            &s.num_reviews.to_string(),
+            &s.streak_length.to_string(),
+            &s.interval.to_string(),
+            &s.e_factor.to_string(),
+        ]);
+    }
+    csv
+}
+
+/// Writes a review history as CSV, with a header row. The moves are separated
+/// by spaces.
+pub fn reviews_csv(reviews: &[ExportedReview]) -> String {
+    let mut csv = String::from("tsumego,reviewDate,grade,solveTimeMs,numWrongMoves,usedHint,moves\n");
+    for r in reviews {
+        write_csv_row(&mut csv, &[
+            &r.tsumego,
+            &r.review_date.to_string(),
+            &r.grade.to_string(),
+            &r.solve_time_ms.map(|t| t.to_string()).unwrap_or_default(),
+            &r.num_wrong_moves.map(|n| n.to_string()).unwrap_or_default(),
+            &r.used_hint.to_string(),
+            &r.moves.as_ref().map(|m| m.join(" ")).unwrap_or_default(),
+        ]);
+    }
+    csv
+}
+
+/// A file in an export archive. Each file is built in memory from the same
+/// `UserExport`, and sent before the next one is built.
+#[derive(Clone, Copy)]
+pub enum ArchiveFile {
+    Readme,
+    Json,
+    StatsCsv,
+    ReviewsCsv,
+}
+
+impl ArchiveFile {
+    /// Every file in an export archive, in order.
+    pub const ALL: [Self; 4] = [Self::Readme, Self::Json, Self::StatsCsv, Self::ReviewsCsv];
+    
+    fn name(self) -> &'static str {
+        match self {
+            Self::Readme => "README.md",
+            Self::Json => "export.json",
+            Self::StatsCsv => "stats.csv",
+            Self::ReviewsCsv => "reviews.csv",
+        }
+    }
+    
+    /// Builds this file from the user's export, as an entry in a tar archive.
+    pub fn to_tar_entry(self, export: &UserExport) -> Result<Vec<u8>> {
+        let contents = match self {
+            Self::Readme => FORMAT_DOCUMENTATION.as_bytes().to_vec(),
+            Self::Json => serde_json::to_vec_pretty(export)
+                .expect("Export should be serialisable"),
+            Self::StatsCsv => stats_csv(&export.stats).into_bytes(),
+            Self::ReviewsCsv => reviews_csv(&export.reviews).into_bytes(),
+        };
+        
+        tar_entry(self.name(), &contents, export.exported_at)
+    }
+}
+
+/// Writes a file as an entry in a tar archive: a header block, followed by
+/// the contents padded to a whole number of blocks.
+fn tar_entry(name: &str, contents: &[u8], modified: time::DateTime) -> Result<Vec<u8>> {
+    let mut header = tar::Header::new_ustar();
+    header.set_path(name)?;
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(modified.and_utc().timestamp().max(0) as u64);
+    header.set_cksum();
+    
+    let padding = contents.len().next_multiple_of(TAR_BLOCK_SIZE) - contents.len();
+    let mut entry = Vec::with_capacity(TAR_BLOCK_SIZE + contents.len() + padding);
+    entry.extend_from_slice(header.as_bytes());
+    entry.extend_from_slice(contents);
+    entry.resize(entry.len() + padding, 0);
+    Ok(entry)
+}
+
+/// Appends a row to a CSV document, quoting fields where necessary.
+fn write_csv_row(csv: &mut String, fields: &[&str]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            csv.push(',');
+        }
+        
+        if field.contains([',', '"', '\n', '\r']) {
+            csv.push('"');
+            csv.push_str(&field.replace('"', "\"\""));
+            csv.push('"');
+        } else {
+            csv.push_str(field);
+        }
+    }
+    csv.push('\n');
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::time;
+    
+    use super::{tar_entry, TAR_END};
+    
+    #[test]
+    fn archive_can_be_read_by_tar() {
+        let mut archive = tar_entry("stats.csv", b"tsumego,grade\n", time::now()).unwrap();
+        archive.extend(tar_entry("README.md", b"", time::now()).unwrap());
+        archive.extend_from_slice(&TAR_END);
+        
+        let mut files = Vec::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut contents = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut contents).unwrap();
+            files.push((name, contents));
+        }
+        
+        assert_eq!(vec![
+            ("stats.csv".to_string(), "tsumego,grade\n".to_string()),
+            ("README.md".to_string(), String::new()),
+        ], files);
+    }
+}
@@ -0,0 +1,165 @@
+use serde_json::Value as JsonValue;
+use sqlx::SqliteConnection;
+
+use crate::{
+    model::{time, Tsumego},
+    result::Result,
+    state::State,
+};
+
+/// No 'I' column, by convention.
+const COLUMN_LETTERS: &[u8] = b"ABCDEFGHJKLMNOPQRSTUVWXYZ";
+
+/// A hint for a tsumego. Hints reveal progressively more of the solution, so
+/// the user can ask for only as much help as they need.
+#[derive(serde::Serialize)]
+pub enum Hint {
+    /// The rectangular region of the board where the solution is played,
+    /// given by its top-left and bottom-right coordinates.
+    #[serde(rename = "region")]
+    Region {from: String, to: String},
+    
+    /// The first move of the solution.
+    #[serde(rename = "firstMove")]
+    FirstMove(String),
+    
+    /// The full solution, including the opponent's responses.
+    #[serde(rename = "line")]
+    Line(Vec<String>),
+}
+
+impl Hint {
+    /// The number of hint levels available for each tsumego.
+    pub const MAX_LEVEL: i64 = 3;
+    
+    /// Gets the hint for a tsumego at the given level, from 1 to `MAX_LEVEL`.
+    /// Returns `None` if the level is out of range, or if the tsumego's
+    /// variation tree has no winning line.
+    pub fn for_tsumego(tsumego: &Tsumego, level: i64) -> Option<Self> {
+        let line = winning_line(&tsumego.tree)?;
+        
+        match level {
+            1 => {
+                let size = tsumego.board.lines().count().saturating_sub(1);
+                let (from, to) = bounding_region(&line, size)?;
+                Some(Hint::Region {from, to})
+            },
+            2 => line.into_iter().next().map(Hint::FirstMove),
+            3 => Some(Hint::Line(line)),
+            _ => None,
+        }
+    }
+    
+    /// Records that the user has been shown a hint for this tsumego, so that
+    /// their next review of it is graded as hinted, whatever the client says.
+    pub async fn record_reveal(state: &State, user_id: i64, tsumego_id: i64) -> Result<()> {
+        let now = time::now();
+        sqlx::query!(
+            "INSERT OR IGNORE INTO hint_reveals (user_id, tsumego_id, revealed)
+                VALUES (?, ?, ?)",
+            user_id,
+            tsumego_id,
+            now,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Determines whether the user has been shown a hint for this tsumego
+    /// since their last review of it, and forgets the hint so that it doesn't
+    /// also count against their next review.
+    pub async fn take_reveal(conn: &mut SqliteConnection, user_id: i64, tsumego_id: i64) -> Result<bool> {
+        let revealed = sqlx::query_scalar!(
+            "DELETE FROM hint_reveals
+                WHERE user_id = ? AND tsumego_id = ?
+                RETURNING revealed",
+            user_id,
+            tsumego_id,
+        )
+            .fetch_optional(conn)
+            .await?;
+        
+        Ok(revealed.is_some())
+    }
+}
+
+/// Finds the first line in a variation tree which ends in a win.
+fn winning_line(tree: &JsonValue) -> Option<Vec<String>> {
+    match tree {
+        JsonValue::String(s) if s == "win" => Some(Vec::new()),
+        JsonValue::Object(children) => children.iter()
+            .find_map(|(coords, child)| {
+                let mut line = winning_line(child)?;
+                line.insert(0, coords.clone());
+                Some(line)
+            }),
+        _ => None,
+    }
+}
+
+/// Finds the region containing every move in the line, with a margin of one
+/// point on each side, clamped to the edges of the board.
+fn bounding_region(line: &[String], size: usize) -> Option<(String, String)> {
+    let points = line.iter()
+        .map(|coords| from_coordinates(coords))
+        .collect::<Option<Vec<_>>>()?;
+    
+    let min_row = points.iter().map(|&(row, _)| row).min()?;
+    let max_row = points.iter().map(|&(row, _)| row).max()?;
+    let min_col = points.iter().map(|&(_, col)| col).min()?;
+    let max_col = points.iter().map(|&(_, col)| col).max()?;
+    
+    let last = size.max(1) - 1;
+    let from = to_coordinates(min_row.saturating_sub(1), min_col.saturating_sub(1))?;
+    let to = to_coordinates((max_row + 1).min(last), (max_col + 1).min(last))?;
+    Some((from, to))
+}
+
+/// Parses a coordinate string like `"A4"` to row and column indices.
+fn from_coordinates(coords: &str) -> Option<(usize, usize)> {
+    let letter = *coords.as_bytes().first()?;
+    let col = COLUMN_LETTERS.iter().position(|&c| c == letter)?;
+    let row = coords[1..].parse::<usize>().ok()?.checked_sub(1)?;
+    Some((row, col))
+}
+
+/// Converts row and column indices to a coordinate string like `"A4"`.
+fn to_coordinates(row: usize, col: usize) -> Option<String> {
+    let letter = *COLUMN_LETTERS.get(col)? as char;
+    Some(format!("{letter}{}", row + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    
+    use super::{bounding_region, winning_line};
+    
+    #[test]
+    fn finds_winning_line() {
+        let tree = json!({
+            "A1": "lose",
+            "B2": {"C3": {"D4": "win"}, "E5": "lose"},
+        });
+        
+        let line = winning_line(&tree).unwrap();
+        assert_eq!(vec!["B2", "C3", "D4"], line);
+    }
+    
+    #[test]
+    fn no_winning_line() {
+        let tree = json!({"A1": "lose", "B2": {"C3": "lose"}});
+        assert!(winning_line(&tree).is_none());
+    }
+    
+    #[test]
+    fn region_is_clamped_to_board() {
+        let line = ["A1", "B3", "J2"].map(String::from);
+        let (from, to) = bounding_region(&line, 9).unwrap();
+        
+        assert_eq!("A1", from);
+        assert_eq!("J4", to);
+    }
+}
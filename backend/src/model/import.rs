@@ -0,0 +1,253 @@
+use std::collections::BTreeSet;
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+use crate::{
+    model::{export::ImportOutcome, time, Grade, UserTsumegoStats},
+    result::Result,
+    state::State,
+};
+
+/// A review imported from another SRS tool, such as Anki. Only the tsumego,
+/// the time and the grade are needed to replay the review.
+pub struct ImportedReview {
+    pub tsumego: String,
+    pub review_date: time::DateTime,
+    pub grade: Grade,
+}
+
+/// An entry from Anki's review log ("revlog"). Anki identifies cards by id,
+/// so the name of the tsumego must be added to each entry, e.g. from the
+/// card's note.
+#[derive(serde::Deserialize)]
+pub struct AnkiRevlogEntry {
+    /// The name of the tsumego which was reviewed.
+    pub tsumego: String,
+    
+    /// The time of the review, in milliseconds since the Unix epoch.
+    pub id: i64,
+    
+    /// The button pressed: 1 for "Again", 2 for "Hard", 3 for "Good" or 4 for
+    /// "Easy". This is 0 for entries which aren't reviews, such as manual
+    /// reschedules.
+    pub ease: i64,
+}
+
+impl ImportedReview {
+    /// Converts an Anki review log entry, returning `None` if it is invalid.
+    pub fn from_anki(entry: AnkiRevlogEntry) -> Option<Self> {
+        let review_date = chrono::DateTime::from_timestamp_millis(entry.id)?
+            .naive_utc();
+        
+        Some(Self {
+            tsumego: entry.tsumego,
+            review_date,
+            grade: grade_from_ease(entry.ease)?,
+        })
+    }
+    
+    /// Converts Anki's review log. Entries with ease 0 record the card being
+    /// rescheduled rather than reviewed, so they are skipped. Returns the
+    /// reviews and the number of skipped entries, or `None` if any other
+    /// entry is invalid.
+    pub fn from_anki_revlog(entries: Vec<AnkiRevlogEntry>) -> Option<(Vec<Self>, i64)> {
+        let mut reviews = Vec::new();
+        let mut num_skipped = 0;
+        
+        for entry in entries {
+            if entry.ease == 0 {
+                num_skipped += 1;
+                continue;
+            }
+            reviews.push(Self::from_anki(entry)?);
+        }
+        
+        Some((reviews, num_skipped))
+    }
+    
+    /// Parses reviews from CSV with the columns `name,date,ease`, where `ease`
+    /// is as in Anki, and `date` is in UTC, either as a date or a date and
+    /// time. The first row may be a header. Returns `None` if any other row is
+    /// invalid.
+    pub fn from_csv(csv: &str) -> Option<Vec<Self>> {
+        let mut reviews = Vec::new();
+        
+        for (i, line) in csv.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            
+            match parse_csv_row(line) {
+                Some(review) => reviews.push(review),
+                // Assume an invalid first row is a header
+                None if i == 0 => continue,
+                None => return None,
+            }
+        }
+        
+        Some(reviews)
+    }
+    
+    /// Adds these reviews to the user's review history, and then rebuilds the
+    /// user's stats for each affected tsumego by replaying their history. A
+    /// review is skipped if the tsumego isn't in the database, or a review of
+    /// the same tsumego at the same time is already recorded.
+    pub async fn import_for_user(state: &State, user_id: i64, reviews: &[Self]) -> Result<ImportOutcome> {
+        let mut outcome = ImportOutcome {
+            num_stats: 0,
+            num_reviews: 0,
+            num_skipped: 0,
+        };
+        let mut tsumego_ids = BTreeSet::new();
+        
+        let mut tx = state.db.begin().await?;
+        
+        for review in reviews {
+            let tsumego_id = sqlx::query_scalar!(
+                "SELECT id FROM tsumego WHERE name = ?",
+                review.tsumego,
+            )
+                .fetch_optional(&mut *tx)
+                .await?;
+            
+            let Some(tsumego_id) = tsumego_id else {
+                outcome.num_skipped += 1;
+                continue;
+            };
+            
+            let grade_int = review.grade as usize as i64;
+            let result = sqlx::query!(
+                "INSERT INTO user_tsumego_reviews
+                    (user_id, tsumego_id, review_date, grade)
+                    SELECT ?, ?, ?, ?
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM user_tsumego_reviews
+                            WHERE user_id = ? AND tsumego_id = ? AND review_date = ?
+                    )",
+                user_id,
+                tsumego_id,
+                review.review_date,
+                grade_int,
+                user_id,
+                tsumego_id,
+                review.review_date,
+            )
+                .execute(&mut *tx)
+                .await?;
+            
+            if result.rows_affected() > 0 {
+                outcome.num_reviews += 1;
+                tsumego_ids.insert(tsumego_id);
+            } else {
+                outcome.num_skipped += 1;
+            }
+        }
+        
+        for &tsumego_id in &tsumego_ids {
+            UserTsumegoStats::rebuild_from_history(state, &mut tx, user_id, tsumego_id)
+                .await?;
+        }
+        outcome.num_stats = tsumego_ids.len() as i64;
+        
+        tx.commit().await?;
+        
+        Ok(outcome)
+    }
+}
+
+/// Converts an Anki "ease" to a grade.
+fn grade_from_ease(ease: i64) -> Option<Grade> {
+    Grade::try_from(ease - 1).ok()
+}
+
+fn parse_csv_row(line: &str) -> Option<ImportedReview> {
+    let fields = split_csv_row(line);
+    let [name, date, ease] = fields.as_slice() else {
+        return None;
+    };
+    
+    Some(ImportedReview {
+        tsumego: name.clone(),
+        review_date: parse_date(date.trim())?,
+        grade: grade_from_ease(ease.trim().parse().ok()?)?,
+    })
+}
+
+/// Parses a date and time in ISO 8601 format, or just a date, in which case
+/// the time is midnight.
+fn parse_date(s: &str) -> Option<time::DateTime> {
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"].into_iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().map(NaiveDateTime::from))
+}
+
+/// Splits one row of a CSV document into fields. Fields may be quoted, in
+/// which case they can contain commas, and quotes are escaped by doubling.
+fn split_csv_row(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_csv_row, AnkiRevlogEntry, Grade, ImportedReview};
+    
+    #[test]
+    fn split_quoted_fields() {
+        let fields = split_csv_row(r#"plain,"with, comma","with ""quotes""""#);
+        assert_eq!(vec!["plain", "with, comma", r#"with "quotes""#], fields);
+    }
+    
+    #[test]
+    fn parse_csv_with_header() {
+        let csv = "name,date,ease\nProblem 1,2024-01-30,3\n\"Problem, 2\",2024-01-31T09:15:00,1\n";
+        let reviews = ImportedReview::from_csv(csv).unwrap();
+        
+        assert_eq!(2, reviews.len());
+        assert_eq!("Problem 1", reviews[0].tsumego);
+        assert!(reviews[0].grade == Grade::Good);
+        assert_eq!("Problem, 2", reviews[1].tsumego);
+        assert!(reviews[1].grade == Grade::Again);
+        assert!(reviews[0].review_date < reviews[1].review_date);
+    }
+    
+    #[test]
+    fn skip_anki_reschedules() {
+        let entry = |id, ease| AnkiRevlogEntry {
+            tsumego: "Problem 1".to_string(),
+            id,
+            ease,
+        };
+        
+        let revlog = vec![entry(1706606100000, 3), entry(1706692500000, 0), entry(1706778900000, 1)];
+        let (reviews, num_skipped) = ImportedReview::from_anki_revlog(revlog).unwrap();
+        assert_eq!(2, reviews.len());
+        assert_eq!(1, num_skipped);
+        assert!(reviews[1].grade == Grade::Again);
+        
+        assert!(ImportedReview::from_anki_revlog(vec![entry(1706606100000, 5)]).is_none());
+    }
+    
+    #[test]
+    fn reject_invalid_ease() {
+        let csv = "Problem 1,2024-01-30,3\nProblem 2,2024-01-30,5\n";
+        assert!(ImportedReview::from_csv(csv).is_none());
+    }
+}
@@ -1,9 +1,21 @@
+mod export;
+mod hint;
+mod import;
+mod preferences;
+mod review;
+mod session;
 mod srs;
 mod stats;
 pub mod time;
 mod tsumego;
 mod user;
 
+pub use export::{reviews_csv, stats_csv, ArchiveFile, UserExport, TAR_END};
+pub use hint::Hint;
+pub use import::{AnkiRevlogEntry, ImportedReview};
+pub use preferences::{Subscriber, UserPreferences};
+pub use review::ReviewDetails;
+pub use session::Session;
 pub use srs::{SrsState, Grade};
 pub use stats::UserTsumegoStats;
 pub use tsumego::Tsumego;
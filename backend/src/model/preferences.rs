@@ -0,0 +1,177 @@
+use chrono::NaiveDate;
+use sqlx::SqliteConnection;
+
+use crate::{
+    auth::MailingList,
+    model::{time, User},
+    result::{AppError, Result},
+    state::State,
+};
+
+/// The number of minutes in a day; reminder times must be less than this.
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// The largest offset from UTC of any time zone, in minutes.
+const MAX_UTC_OFFSET_MINUTES: i64 = 14 * 60;
+
+/// A user's preferences, including which optional emails they want to
+/// receive.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct UserPreferences {
+    /// The user's offset from UTC in minutes, e.g. 60 for UTC+1. This is used
+    /// to interpret times of day, such as `reminder_time`.
+    #[serde(rename = "utcOffsetMinutes")]
+    pub utc_offset_minutes: i64,
+    
+    /// Whether the user wants a reminder email on days when they have reviews
+    /// due, if they haven't studied yet.
+    #[serde(rename = "remindersEnabled")]
+    pub reminders_enabled: bool,
+    
+    /// The local time of day to send reminders, in minutes after midnight.
+    #[serde(rename = "reminderTime")]
+    pub reminder_time: i64,
+    
+    /// Whether the user wants a weekly email summarising their progress.
+    #[serde(rename = "digestEnabled")]
+    pub digest_enabled: bool,
+}
+
+/// A user who has opted in to a mailing list, and can be sent its emails.
+pub struct Subscriber {
+    pub user: User,
+    pub prefs: UserPreferences,
+    
+    /// The user's local date on which they were most recently sent an email
+    /// from this list, if ever.
+    pub last_sent_date: Option<NaiveDate>,
+}
+
+impl Default for UserPreferences {
+    fn default() -> Self {
+        Self {
+            utc_offset_minutes: 0,
+            reminders_enabled: false,
+            // 6pm
+            reminder_time: 18 * 60,
+            digest_enabled: false,
+        }
+    }
+}
+
+impl UserPreferences {
+    /// Gets the user's preferences, or the defaults if they haven't set any.
+    pub async fn get_for_user(conn: &mut SqliteConnection, user_id: i64) -> Result<Self> {
+        let prefs = sqlx::query_as!(
+            Self,
+            "SELECT utc_offset_minutes, reminders_enabled, reminder_time, digest_enabled
+                FROM user_preferences
+                WHERE user_id = ?",
+            user_id,
+        )
+            .fetch_optional(conn)
+            .await?;
+        
+        Ok(prefs.unwrap_or_default())
+    }
+    
+    /// Saves the user's preferences. Returns an HTTP "400 Bad Request" error
+    /// if any of them are out of range.
+    pub async fn save(&self, state: &State, user_id: i64) -> Result<()> {
+        if !(0..MINUTES_PER_DAY).contains(&self.reminder_time)
+            || self.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES
+        {
+            return Err(AppError::BAD_REQUEST);
+        }
+        
+        sqlx::query!(
+            "INSERT INTO user_preferences (user_id, utc_offset_minutes, reminders_enabled, reminder_time, digest_enabled)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    utc_offset_minutes = excluded.utc_offset_minutes,
+                    reminders_enabled = excluded.reminders_enabled,
+                    reminder_time = excluded.reminder_time,
+                    digest_enabled = excluded.digest_enabled",
+            user_id,
+            self.utc_offset_minutes,
+            self.reminders_enabled,
+            self.reminder_time,
+            self.digest_enabled,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Stops sending reminder emails to the user, e.g. when they follow the
+    /// unsubscribe link in one.
+    pub async fn disable_reminders(state: &State, user_id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE user_preferences SET reminders_enabled = 0 WHERE user_id = ?",
+            user_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Stops sending weekly digest emails to the user, e.g. when they follow
+    /// the unsubscribe link in one.
+    pub async fn disable_digest(state: &State, user_id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE user_preferences SET digest_enabled = 0 WHERE user_id = ?",
+            user_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Fetches every user who has opted in to a mailing list, and whose
+    /// account is verified and not suspended.
+    pub async fn get_subscribers(state: &State, list: MailingList) -> Result<Vec<Subscriber>> {
+        let is_digest = list == MailingList::Digest;
+        let subscribers = sqlx::query!(
+            r#"SELECT users.id, users.email, users.display_name, users.is_admin, users.require_password_change,
+                    p.utc_offset_minutes, p.reminders_enabled, p.reminder_time, p.digest_enabled,
+                    CASE WHEN ? THEN p.last_digest_date ELSE p.last_reminder_date END AS "last_sent_date: NaiveDate"
+                FROM user_preferences p INNER JOIN users ON p.user_id = users.id
+                WHERE (CASE WHEN ? THEN p.digest_enabled ELSE p.reminders_enabled END) = 1
+                AND users.is_suspended = 0
+                AND users.require_email_verification = 0"#,
+            is_digest,
+            is_digest,
+        )
+            .fetch_all(&state.db)
+            .await?
+            .into_iter()
+            .map(|s| Subscriber {
+                user: User {
+                    id: s.id,
+                    email: s.email,
+                    display_name: s.display_name,
+                    is_admin: s.is_admin,
+                    require_password_change: s.require_password_change,
+                    two_factor_pending: false,
+                },
+                prefs: Self {
+                    utc_offset_minutes: s.utc_offset_minutes,
+                    reminders_enabled: s.reminders_enabled,
+                    reminder_time: s.reminder_time,
+                    digest_enabled: s.digest_enabled,
+                },
+                last_sent_date: s.last_sent_date,
+            })
+            .collect();
+        
+        Ok(subscribers)
+    }
+    
+    /// Converts a UTC time to the user's local time.
+    pub fn local_time(&self, utc: time::DateTime) -> time::DateTime {
+        utc + chrono::TimeDelta::minutes(self.utc_offset_minutes)
+    }
+}
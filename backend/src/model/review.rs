@@ -0,0 +1,37 @@
+/// Details of a single attempt at a tsumego, which are recorded alongside the
+/// grade in the user's review history. Every field is optional, since older
+/// clients only send the grade.
+#[derive(Default, serde::Deserialize)]
+pub struct ReviewDetails {
+    /// The time the user took to solve the tsumego, in milliseconds.
+    #[serde(rename = "solveTimeMs")]
+    pub solve_time_ms: Option<i64>,
+    
+    /// The number of wrong moves the user tried before solving the tsumego,
+    /// or giving up.
+    #[serde(rename = "numWrongMoves")]
+    pub num_wrong_moves: Option<i64>,
+    
+    /// Whether the user was shown a hint during this attempt.
+    #[serde(rename = "usedHint", default)]
+    pub used_hint: bool,
+    
+    /// The sequence of moves played during this attempt, as coordinates like
+    /// `"C3"`, including the opponent's responses.
+    pub moves: Option<Vec<String>>,
+}
+
+impl ReviewDetails {
+    /// Determines whether these details are plausible. The client is not
+    /// trusted to send sensible values.
+    pub fn is_valid(&self) -> bool {
+        self.solve_time_ms.is_none_or(|t| t >= 0)
+            && self.num_wrong_moves.is_none_or(|n| n >= 0)
+    }
+    
+    /// Encodes the move sequence as JSON, for storing in the database.
+    pub fn moves_json(&self) -> Option<String> {
+        self.moves.as_ref()
+            .map(|moves| serde_json::Value::from(moves.as_slice()).to_string())
+    }
+}
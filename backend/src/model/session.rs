@@ -0,0 +1,109 @@
+use crate::{
+    model::time,
+    result::Result,
+    state::State,
+};
+
+/// How often a session's "last seen" time and client details are updated.
+/// Updating them on every request would mean a database write per request.
+const LAST_SEEN_RESOLUTION_MINUTES: f64 = 5.0;
+
+/// Details about one of a user's active sessions, so that they can recognise
+/// sessions which aren't theirs and revoke them.
+#[derive(serde::Serialize)]
+pub struct Session {
+    pub id: i64,
+    
+    /// When the session began, if known. Sessions which began before these
+    /// details were recorded have no creation time.
+    pub created: Option<time::DateTime>,
+    
+    /// The last time the session was used, accurate to within a few minutes.
+    #[serde(rename = "lastSeen")]
+    pub last_seen: Option<time::DateTime>,
+    
+    /// The `User-Agent` header of the last request in this session.
+    #[serde(rename = "userAgent")]
+    pub user_agent: Option<String>,
+    
+    /// The IP address of the last request in this session.
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+    
+    pub expires: time::DateTime,
+    
+    /// Whether this is the session making the current request.
+    #[serde(rename = "isCurrent")]
+    pub is_current: bool,
+}
+
+impl Session {
+    /// Fetches all of the user's unexpired sessions, most recently used first.
+    pub async fn get_all_for_user(state: &State, user_id: i64, current_session_id: i64) -> Result<Vec<Self>> {
+        let now = time::now();
+        
+        let sessions = sqlx::query_as!(
+            Self,
+            r#"SELECT id, created, last_seen, user_agent, ip_address, expires,
+                    id = ? AS "is_current!: bool"
+                FROM sessions
+                WHERE user_id = ? AND expires > ?
+                ORDER BY last_seen DESC"#,
+            current_session_id,
+            user_id,
+            now,
+        )
+            .fetch_all(&state.db)
+            .await?;
+        
+        Ok(sessions)
+    }
+    
+    /// Records that a session has been used by the given client. This only
+    /// writes to the database if the session hasn't been seen recently.
+    pub async fn touch(state: &State, session_id: i64, user_agent: Option<&str>, ip_address: Option<&str>) -> Result<()> {
+        let now = time::now();
+        let seen_recently = time::add_days(now, -LAST_SEEN_RESOLUTION_MINUTES / (24.0 * 60.0));
+        
+        sqlx::query!(
+            "UPDATE sessions SET last_seen = ?, user_agent = ?, ip_address = ?
+                WHERE id = ? AND (last_seen IS NULL OR last_seen < ?)",
+            now,
+            user_agent,
+            ip_address,
+            session_id,
+            seen_recently,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Revokes one of the user's sessions. Returns `false` if the user has no
+    /// session with that id.
+    pub async fn revoke(state: &State, user_id: i64, session_id: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE id = ? AND user_id = ?",
+            session_id,
+            user_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(result.rows_affected() > 0)
+    }
+    
+    /// Revokes all of the user's sessions, except the current one.
+    pub async fn revoke_all_others(state: &State, user_id: i64, current_session_id: i64) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM sessions WHERE user_id = ? AND id != ?",
+            user_id,
+            current_session_id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
+}
@@ -50,7 +50,7 @@ pub enum LearningState {
     Mature = 2,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
 pub enum Grade {
     Again = 0,
     Hard = 1,
@@ -58,6 +58,21 @@ pub enum Grade {
     Easy = 3,
 }
 
+impl TryFrom<i64> for Grade {
+    type Error = ();
+    
+    /// Converts a grade from its representation in the database.
+    fn try_from(value: i64) -> Result<Self, ()> {
+        match value {
+            0 => Ok(Grade::Again),
+            1 => Ok(Grade::Hard),
+            2 => Ok(Grade::Good),
+            3 => Ok(Grade::Easy),
+            _ => Err(()),
+        }
+    }
+}
+
 impl SrsState {
     /// Returns a new SRS state for the user's first review of a tsumego, when
     /// there is no priod state.
@@ -102,7 +117,12 @@ impl SrsState {
             next.interval = FIRST_INTERVAL;
             
             if !previous.is_learning() {
-                next.e_factor = clamp_e_factor(previous.e_factor - 0.2);
+                // Forgetting a tsumego after a long absence says less about
+                // how hard it is than forgetting it on time, so the penalty
+                // is scaled down when the review is late. This matters for
+                // reviews deferred by backlog recovery.
+                let on_time_fraction = previous.interval / f64::max(days_since_last_review, previous.interval);
+                next.e_factor = clamp_e_factor(previous.e_factor - 0.2 * on_time_fraction);
             }
         } else {
             // Passed
@@ -274,4 +294,21 @@ mod test {
         assert!(late.interval >= on_time.interval);
         assert!(late.e_factor >= on_time.e_factor);
     }
+    
+    #[test]
+    fn late_failure() {
+        let initial = SrsState::default()
+            .update_on_review(1.0, Grade::Good)
+            .update_on_review(2.0, Grade::Good)
+            .update_on_review(4.0, Grade::Good);
+        
+        let on_time = initial.update_on_review(initial.interval, Grade::Again);
+        let late = initial.update_on_review(initial.interval + 30.0, Grade::Again);
+        
+        // Forgetting after a long absence shouldn't be penalised more than
+        // forgetting on time
+        assert!(late.e_factor >= on_time.e_factor);
+        assert!(late.e_factor <= initial.e_factor);
+        assert_eq!(on_time.interval, late.interval);
+    }
 }
@@ -1,7 +1,8 @@
 use rand::Rng;
+use sqlx::SqliteConnection;
 
 use crate::{
-    model::{srs::LearningState, time, Grade, SrsState},
+    model::{srs::LearningState, time, Grade, Hint, ReviewDetails, SrsState},
     state::State,
     result::Result,
 };
@@ -97,7 +98,7 @@ impl UserTsumegoStats {
         Ok(stats)
     }
     
-    pub async fn get(state: &State, user_id: i64, tsumego_id: i64) -> Result<Option<Self>> {
+    pub async fn get(conn: &mut SqliteConnection, user_id: i64, tsumego_id: i64) -> Result<Option<Self>> {
         let stats = sqlx::query_as!(
             FlatStats,
             "SELECT * FROM user_tsumego_stats
@@ -106,7 +107,7 @@ impl UserTsumegoStats {
             user_id,
             tsumego_id,
         )
-            .fetch_optional(&state.db)
+            .fetch_optional(conn)
             .await?
             .map(Self::from);
         
@@ -117,25 +118,44 @@ impl UserTsumegoStats {
         self.review_due.is_some()
     }
     
-    pub async fn update_on_review(state: &State, user_id: i64, tsumego_id: i64, grade: Grade) -> Result<Self> {
+    pub async fn update_on_review(state: &State, user_id: i64, tsumego_id: i64, grade: Grade, details: &ReviewDetails) -> Result<Self> {
         let now = time::now();
+        let mut tx = state.db.begin().await?;
+        
+        // A user who needed a hint didn't solve the tsumego unaided, so the
+        // review is graded "Hard" at best. Hints are recorded when they are
+        // revealed, so the client can't avoid the cap by not reporting them.
+        // The hint is only forgotten if this review is recorded.
+        let used_hint = Hint::take_reveal(&mut tx, user_id, tsumego_id)
+            .await?
+            || details.used_hint;
+        let grade = if used_hint {
+            grade.min(Grade::Hard)
+        } else {
+            grade
+        };
         
         // Insert a record of this review.
         let grade_int = grade as usize as i64;
+        let moves = details.moves_json();
         sqlx::query!(
             "INSERT INTO user_tsumego_reviews
-                (user_id, tsumego_id, review_date, grade)
-                VALUES (?, ?, ?, ?)",
+                (user_id, tsumego_id, review_date, grade, solve_time_ms, num_wrong_moves, used_hint, moves)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
             user_id,
             tsumego_id,
             now,
             grade_int,
+            details.solve_time_ms,
+            details.num_wrong_moves,
+            used_hint,
+            moves,
         )
-            .execute(&state.db)
+            .execute(&mut *tx)
             .await?;
         
         // Insert or update statistics for this tsumego.
-        let stats = Self::get(state, user_id, tsumego_id)
+        let stats = Self::get(&mut tx, user_id, tsumego_id)
             .await?;
         
         let srs_state = match stats.as_ref() {
@@ -156,14 +176,7 @@ impl UserTsumegoStats {
             // due date
             None
         } else {
-            // Add random fuzz to the interval. This prevents "bunching up";
-            // otherwise, tsumego prompted on the same day would continue to be
-            // prompted together in the future.
-            let fuzz_factor = state.cfg.srs_interval_fuzz_factor;
-            let fuzz_range = (1.0 - fuzz_factor)..(1.0 + fuzz_factor);
-            let fuzz = rand::thread_rng().gen_range(fuzz_range);
-            
-            Some(time::add_days(now, srs_state.interval * fuzz))
+            Some(next_review_due(state, now, &srs_state))
         };
         
         let id = sqlx::query_scalar!(
@@ -180,7 +193,7 @@ impl UserTsumegoStats {
             srs_state.interval,
             srs_state.e_factor,
         )
-            .fetch_one(&state.db)
+            .fetch_one(&mut *tx)
             .await?;
         
         let new_stats = match stats {
@@ -201,8 +214,170 @@ impl UserTsumegoStats {
             },
         };
         
+        tx.commit().await?;
+        
         Ok(new_stats)
     }
+    
+    /// Rebuilds this user's stats for a tsumego by replaying their whole
+    /// review history through the SRS algorithm. This is needed when reviews
+    /// are imported from elsewhere, since they may predate the user's existing
+    /// reviews. Does nothing if the user has never reviewed this tsumego.
+    pub async fn rebuild_from_history(state: &State, conn: &mut SqliteConnection, user_id: i64, tsumego_id: i64) -> Result<()> {
+        let reviews = sqlx::query!(
+            "SELECT review_date, grade FROM user_tsumego_reviews
+                WHERE user_id = ? AND tsumego_id = ?
+                ORDER BY review_date",
+            user_id,
+            tsumego_id,
+        )
+            .fetch_all(&mut *conn)
+            .await?;
+        
+        let mut srs_state = SrsState::default();
+        let mut last_review_date = None;
+        
+        for review in reviews {
+            let Ok(grade) = Grade::try_from(review.grade) else {
+                continue;
+            };
+            
+            let days_since_last_review = last_review_date
+                .map_or(0.0, |last| time::delta_days(last, review.review_date));
+            srs_state = srs_state.update_on_review(days_since_last_review, grade);
+            last_review_date = Some(review.review_date);
+        }
+        
+        let Some(last_review_date) = last_review_date else {
+            return Ok(());
+        };
+        
+        // `None` if there are no stats yet, or `Some(None)` if the tsumego is
+        // out of rotation for this user
+        let prior_review_due = sqlx::query_scalar!(
+            "SELECT review_due FROM user_tsumego_stats
+                WHERE user_id = ? AND tsumego_id = ?",
+            user_id,
+            tsumego_id,
+        )
+            .fetch_optional(&mut *conn)
+            .await?;
+        
+        let review_due = match prior_review_due {
+            Some(None) => None,
+            _ => Some(next_review_due(state, last_review_date, &srs_state)),
+        };
+        
+        sqlx::query!(
+            "INSERT OR REPLACE INTO user_tsumego_stats
+                (user_id, tsumego_id, last_review_date, review_due, num_reviews, streak_length, interval, e_factor)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            user_id,
+            tsumego_id,
+            last_review_date,
+            review_due,
+            srs_state.num_reviews,
+            srs_state.streak_length,
+            srs_state.interval,
+            srs_state.e_factor,
+        )
+            .execute(&mut *conn)
+            .await?;
+        
+        Ok(())
+    }
+    
+    /// Spreads this user's overdue reviews across the next `days` days, by
+    /// rewriting their due dates, so that a user returning after a long
+    /// absence isn't faced with their whole backlog at once. The most overdue
+    /// tsumego remain due today; the rest are deferred so that, counting the
+    /// reviews which were already due on each day, no day has more than a
+    /// fair share.
+    /// 
+    /// The last review dates are unchanged, so the SRS algorithm still sees
+    /// how late each review really was. Returns the number of deferred
+    /// reviews.
+    pub async fn spread_overdue_reviews(state: &State, user_id: i64, days: i64) -> Result<i64> {
+        let now = time::now();
+        let start_of_today = time::start_of_day(now);
+        let end = time::add_days(start_of_today, days as f64);
+        
+        let mut tx = state.db.begin().await?;
+        
+        let overdue = sqlx::query_scalar!(
+            "SELECT id FROM user_tsumego_stats
+                WHERE user_id = ? AND review_due <= ?
+                ORDER BY review_due",
+            user_id,
+            now,
+        )
+            .fetch_all(&mut *tx)
+            .await?;
+        
+        // Count the reviews which are already due on each of the days
+        let mut already_due = vec![0; days as usize];
+        let upcoming = sqlx::query_scalar!(
+            r#"SELECT review_due AS "review_due!" FROM user_tsumego_stats
+                WHERE user_id = ? AND review_due > ? AND review_due < ?"#,
+            user_id,
+            now,
+            end,
+        )
+            .fetch_all(&mut *tx)
+            .await?;
+        for review_due in upcoming {
+            let day = time::delta_days(start_of_today, review_due) as usize;
+            if let Some(count) = already_due.get_mut(day) {
+                *count += 1;
+            }
+        }
+        
+        // Each day is filled up to the same total, so the days' spare
+        // capacity is always enough for every overdue review
+        let total = overdue.len() + already_due.iter().sum::<usize>();
+        let per_day = total.div_ceil(days as usize);
+        let mut remaining = overdue.as_slice();
+        let mut num_deferred = 0;
+        
+        for (i, &count) in already_due.iter().enumerate() {
+            let (chunk, rest) = remaining.split_at(per_day.saturating_sub(count).min(remaining.len()));
+            remaining = rest;
+            
+            // The first day's share is left as it is, since it is already due
+            if i == 0 {
+                continue;
+            }
+            
+            let review_due = time::add_days(start_of_today, i as f64);
+            for id in chunk {
+                sqlx::query!(
+                    "UPDATE user_tsumego_stats SET review_due = ? WHERE id = ?",
+                    review_due,
+                    id,
+                )
+                    .execute(&mut *tx)
+                    .await?;
+                num_deferred += 1;
+            }
+        }
+        
+        tx.commit().await?;
+        
+        Ok(num_deferred)
+    }
+}
+
+/// Determines when a tsumego should next be reviewed, after a review at the
+/// given time.
+fn next_review_due(state: &State, last_review_date: time::DateTime, srs_state: &SrsState) -> time::DateTime {
+    // Add random fuzz to the interval. This prevents "bunching up"; otherwise,
+    // tsumego prompted on the same day would continue to be prompted together
+    // in the future.
+    let fuzz_factor = state.cfg.srs_interval_fuzz_factor;
+    let fuzz_range = (1.0 - fuzz_factor)..(1.0 + fuzz_factor);
+    let fuzz = rand::thread_rng().gen_range(fuzz_range);
+    
+    time::add_days(last_review_date, srs_state.interval * fuzz)
 }
 
 fn get_learning_state(review_due: &Option<time::DateTime>, srs_state: &SrsState) -> Option<LearningState> {
@@ -212,3 +387,60 @@ fn get_learning_state(review_due: &Option<time::DateTime>, srs_state: &SrsState)
         Some(srs_state.learning_state())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        mailer::MemoryMailer,
+        model::time,
+        state::{self, insert_test_user},
+    };
+    
+    use super::UserTsumegoStats;
+    
+    #[actix_web::test]
+    async fn spreading_counts_reviews_already_due() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
+        let start_of_today = time::start_of_day(time::now());
+        
+        // Six reviews are overdue, and two more are due tomorrow
+        let overdue = time::add_days(start_of_today, -1.0);
+        let tomorrow = time::add_days(start_of_today, 1.5);
+        for id in 1..=8 {
+            let review_due = if id <= 6 { overdue } else { tomorrow };
+            sqlx::query!("INSERT INTO tsumego (id, name, board, tree) VALUES (?, ?, '', '')", id, id)
+                .execute(&state.db)
+                .await
+                .unwrap();
+            sqlx::query!(
+                "INSERT INTO user_tsumego_stats
+                    (user_id, tsumego_id, last_review_date, review_due, num_reviews, streak_length, interval, e_factor)
+                    VALUES (?, ?, ?, ?, 1, 1, 1.0, 2.5)",
+                user.id,
+                id,
+                overdue,
+                review_due,
+            )
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+        
+        let num_deferred = UserTsumegoStats::spread_overdue_reviews(&state, user.id, 3)
+            .await
+            .unwrap();
+        assert_eq!(3, num_deferred);
+        
+        let mut per_day = [0; 3];
+        let due = sqlx::query_scalar!(r#"SELECT review_due AS "review_due!" FROM user_tsumego_stats"#)
+            .fetch_all(&state.db)
+            .await
+            .unwrap();
+        for review_due in due {
+            let day = time::delta_days(start_of_today, review_due).max(0.0) as usize;
+            per_day[day] += 1;
+        }
+        assert_eq!([3, 3, 2], per_day);
+    }
+}
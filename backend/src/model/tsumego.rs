@@ -24,7 +24,7 @@ impl Tsumego {
     pub async fn require_by_id(state: &State, id: i64) -> Result<Self> {
         Self::get_by_id(state, id)
             .await?
-            .map_or(Err(sqlx::Error::RowNotFound.into()), Ok)
+            .ok_or(sqlx::Error::RowNotFound.into())
     }
     
     /// Fetches a Tsumego from the database by its id, returning `None` if the
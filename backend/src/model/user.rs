@@ -12,6 +12,16 @@ pub struct User {
     pub display_name: String,
     #[serde(rename = "isAdmin")]
     pub is_admin: bool,
+    /// Whether the user must choose a new password before they can continue,
+    /// e.g. after completing a password reset challenge.
+    #[serde(rename = "requirePasswordChange")]
+    pub require_password_change: bool,
+    /// Whether the user has logged in with their password or an email link,
+    /// but still needs to give a code for two-factor authentication. This
+    /// describes the user's current session, so is only ever `true` for the
+    /// authenticated user.
+    #[serde(rename = "twoFactorPending")]
+    pub two_factor_pending: bool,
 }
 
 impl User {
@@ -20,16 +30,18 @@ impl User {
     pub async fn require_by_id(state: &State, id: i64) -> Result<Self> {
         Self::get_by_id(state, id)
             .await?
-            .map_or(Err(sqlx::Error::RowNotFound.into()), Ok)
+            .ok_or(sqlx::Error::RowNotFound.into())
     }
     
     /// Retrieves the active user with the given id, if they exist.
     pub async fn get_by_id(state: &State, id: i64) -> Result<Option<Self>> {
         let user = sqlx::query_as!(
             Self,
-            "SELECT id, email, display_name, is_admin FROM users
+            r#"SELECT id, email, display_name, is_admin, require_password_change,
+                    0 AS "two_factor_pending!: bool"
+                FROM users
                 WHERE id = ?
-                AND require_email_verification = 0",
+                AND require_email_verification = 0"#,
             id,
         )
             .fetch_optional(&state.db)
@@ -42,15 +54,49 @@ impl User {
     pub async fn get_all(state: &State) -> Result<Vec<Self>> {
         let users = sqlx::query_as!(
             Self,
-            "SELECT id, email, display_name, is_admin FROM users
+            r#"SELECT id, email, display_name, is_admin, require_password_change,
+                    0 AS "two_factor_pending!: bool"
+                FROM users
                 WHERE require_email_verification = 0
-                ORDER by id",
+                ORDER by id"#,
         )
             .fetch_all(&state.db)
             .await?;
         
         Ok(users)
     }
+    
+    /// Updates this user's email address in the database. Returns `false`
+    /// without updating if the address is already in use by another user.
+    pub async fn update_email(&self, state: &State) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE users SET email = ? WHERE id = ?",
+            self.email,
+            self.id,
+        )
+            .execute(&state.db)
+            .await;
+        
+        match result {
+            Ok(_) => Ok(true),
+            // The `users_email_unique` index is case-insensitive
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+    
+    /// Updates this user's display name in the database.
+    pub async fn update_display_name(&self, state: &State, display_name: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE users SET display_name = ? WHERE id = ?",
+            display_name,
+            self.id,
+        )
+            .execute(&state.db)
+            .await?;
+        
+        Ok(())
+    }
 }
 
 /// Details about a user including their statistics for today's study.
@@ -1,28 +1,421 @@
-use actix_web::rt::{spawn, time};
-use std::time::Duration;
+use actix_web::rt::{spawn, time::{sleep, timeout}};
+use std::{future::Future, pin::Pin, str::FromStr, time::Duration};
 
 use crate::{
+    model::time,
     result::Result,
     state::State,
 };
 
+/// How often to check for reminder and digest emails which are due. They are
+/// checked often, so that they are sent close to each user's chosen time.
+const REMINDER_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Cleanup jobs run at the start of every hour.
+const HOURLY: &str = "0 0 * * * *";
+
+/// Database backups are taken at a quiet time of day.
+const DAILY_BACKUP: &str = "0 0 3 * * *";
+
+/// The longest time a job's loop sleeps before checking its schedule again.
+/// This means a job notices if it was triggered manually, or if the system
+/// clock changes.
+const MAX_SLEEP: Duration = Duration::from_secs(60 * 60);
+
+/// How long a job's lease lasts beyond its timeout. The lease is normally
+/// released when the job finishes, so it only expires if the server process
+/// running the job stopped.
+const LEASE_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long a job's loop waits after failing to read its schedule.
+const ERROR_DELAY: Duration = Duration::from_secs(60);
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<()>>>>;
+
+/// When a periodic job should run.
+pub enum Schedule {
+    /// Runs the job repeatedly, with the given time between starts.
+    Every(Duration),
+    /// Runs the job at the times matching a cron expression, in UTC. The
+    /// expression includes a seconds field.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    fn cron(expr: &str) -> Self {
+        let schedule = cron::Schedule::from_str(expr)
+            .expect("Job schedule should be a valid cron expression");
+        Self::Cron(Box::new(schedule))
+    }
+    
+    /// Computes when a job should next run, given when it last started. The
+    /// result may be in the past, if a run was missed while the server was
+    /// down.
+    fn next_run(&self, last_started: Option<time::DateTime>, now: time::DateTime) -> time::DateTime {
+        match self {
+            Schedule::Every(interval) => last_started.map_or(now, |t| {
+                t + chrono::TimeDelta::from_std(*interval)
+                    .expect("Job interval should be in range")
+            }),
+            Schedule::Cron(schedule) => {
+                let after = last_started.unwrap_or(now);
+                schedule.after(&after.and_utc())
+                    .next()
+                    .map_or(time::DateTime::MAX, |t| t.naive_utc())
+            },
+        }
+    }
+    
+    fn describe(&self) -> String {
+        match self {
+            Schedule::Every(interval) => format!("every {}s", interval.as_secs()),
+            Schedule::Cron(schedule) => schedule.to_string(),
+        }
+    }
+}
+
+/// A named job which is run periodically.
+pub struct Job {
+    name: &'static str,
+    schedule: Schedule,
+    
+    /// The longest time a run of this job may take before it is cancelled.
+    timeout: Duration,
+    
+    /// Whether the job only affects this server process, so every process
+    /// must run it. Other jobs run in only one process at a time, and their
+    /// runs are recorded in the database.
+    local: bool,
+    run: fn(State) -> JobFuture,
+}
+
+/// Returns every periodic job.
+pub fn registry(state: &State) -> Vec<Job> {
+    let mut jobs = vec![
+        Job {
+            name: "send_mail",
+            // Outbound mail is sent often, so that emailed links arrive
+            // promptly
+            schedule: Schedule::Every(Duration::from_secs(state.cfg.mail_queue_interval_secs)),
+            timeout: Duration::from_secs(5 * 60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { crate::mail_queue::send_due(&state).await }) },
+        },
+        Job {
+            name: "send_reminders",
+            schedule: Schedule::Every(Duration::from_secs(REMINDER_INTERVAL_SECS)),
+            timeout: Duration::from_secs(5 * 60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { crate::reminders::send_due_reminders(&state).await }) },
+        },
+        Job {
+            name: "send_digests",
+            schedule: Schedule::Every(Duration::from_secs(REMINDER_INTERVAL_SECS)),
+            timeout: Duration::from_secs(10 * 60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { crate::digest::send_due_digests(&state).await }) },
+        },
+        Job {
+            name: "delete_expired_sessions",
+            schedule: Schedule::cron(HOURLY),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { state.delete_all_expired_sessions().await }) },
+        },
+        Job {
+            name: "delete_expired_challenges",
+            schedule: Schedule::cron(HOURLY),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { state.delete_expired_challenges().await }) },
+        },
+        Job {
+            name: "delete_expired_oidc_logins",
+            schedule: Schedule::cron(HOURLY),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { state.delete_expired_oidc_logins().await }) },
+        },
+        Job {
+            name: "delete_expired_mail",
+            schedule: Schedule::cron(HOURLY),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { crate::mail_queue::delete_expired(&state).await }) },
+        },
+        Job {
+            name: "forget_stale_rate_limits",
+            schedule: Schedule::cron(HOURLY),
+            timeout: Duration::from_secs(60),
+            // Each process has its own rate limiter
+            local: true,
+            run: |state| -> JobFuture {
+                Box::pin(async move {
+                    state.rate_limiter.forget_stale();
+                    Ok(())
+                })
+            },
+        },
+    ];
+    
+    if state.cfg.backup_dir.is_some() {
+        jobs.push(Job {
+            name: "backup_database",
+            schedule: Schedule::cron(DAILY_BACKUP),
+            timeout: Duration::from_secs(30 * 60),
+            local: false,
+            run: |state| -> JobFuture { Box::pin(async move { crate::backup::run_scheduled_backup(&state).await }) },
+        });
+    }
+    
+    jobs
+}
+
+/// Starts every periodic job on its own loop, so that jobs run concurrently.
 pub fn start(state: State) {
-    spawn(async move {
-        // Once per hour
-        let mut interval = time::interval(Duration::from_secs(60 * 60));
-        
-        loop {
-            interval.tick().await;
-            
-            state.delete_all_expired_sessions()
-                .await
-                .report_if_err();
+    for job in registry(&state) {
+        let state = state.clone();
+        spawn(async move {
+            // Local jobs aren't recorded in the database
+            let mut local_last_started = None;
             
-            state.delete_expired_challenges()
-                .await
-                .report_if_err();
-        }
+            loop {
+                let delay = run_if_due(&state, &job, &mut local_last_started)
+                    .await;
+                sleep(delay).await;
+            }
+        });
+    }
+}
+
+/// Runs a job if it is due, and returns how long to wait before checking its
+/// schedule again.
+async fn run_if_due(state: &State, job: &Job, local_last_started: &mut Option<time::DateTime>) -> Duration {
+    let last_started = if job.local {
+        Ok(*local_last_started)
+    } else {
+        get_last_started(state, job).await
+    };
+    
+    let last_started = match last_started {
+        Ok(last_started) => last_started,
+        Err(e) => {
+            log::error!("Failed to read schedule for job {}: {e:?}", job.name);
+            return ERROR_DELAY;
+        },
+    };
+    
+    if let Some(delay) = time_until_due(job, last_started) {
+        return delay.min(MAX_SLEEP);
+    }
+    
+    if job.local {
+        *local_last_started = Some(time::now());
+    }
+    
+    match run_job(state, job, Claim::Scheduled(last_started)).await {
+        Ok(true) => Duration::ZERO,
+        // Another run holds the lease, in another process or triggered
+        // manually, and may hold it for as long as its timeout. Wait for a
+        // whole period of the schedule, rather than checking again straight
+        // away until the lease is released.
+        Ok(false) => time_until_due(job, Some(time::now()))
+            .unwrap_or(ERROR_DELAY)
+            .min(MAX_SLEEP),
+        Err(e) => {
+            log::error!("Error in periodic job {}: {e:?}", job.name);
+            ERROR_DELAY
+        },
+    }
+}
+
+/// Runs a job now, regardless of its schedule, unless it is already running.
+/// Returns `false` if there is no job with this name.
+pub fn trigger(state: &State, name: &str) -> bool {
+    let Some(job) = registry(state).into_iter().find(|job| job.name == name) else {
+        return false;
+    };
+    
+    let state = state.clone();
+    spawn(async move {
+        run_job(&state, &job, Claim::Manual)
+            .await
+            .report_if_err();
     });
+    true
+}
+
+/// Fetches when the job last started, in any server process.
+async fn get_last_started(state: &State, job: &Job) -> Result<Option<time::DateTime>> {
+    let last_started = sqlx::query_scalar!(
+        "SELECT last_started FROM periodic_jobs WHERE name = ?",
+        job.name,
+    )
+        .fetch_optional(&state.db)
+        .await?;
+    
+    Ok(last_started)
+}
+
+/// Returns how long until the job is next due, or `None` if it is due now.
+fn time_until_due(job: &Job, last_started: Option<time::DateTime>) -> Option<Duration> {
+    let now = time::now();
+    let next_run = job.schedule.next_run(last_started, now);
+    (next_run > now).then(|| (next_run - now).to_std().unwrap_or_default())
+}
+
+/// The conditions under which a run of a job may take the job's lease.
+enum Claim {
+    /// A scheduled run, which only goes ahead if the job hasn't started since
+    /// the given time. This stops two processes which both saw that the job
+    /// was due from both running it.
+    Scheduled(Option<time::DateTime>),
+    /// A manual run, which goes ahead whenever the job isn't running.
+    Manual,
+}
+
+/// Takes the lease on a job for this server process, and records that the
+/// job has started. Returns `false` if another run holds an unexpired lease,
+/// or if a scheduled run is no longer due.
+async fn take_lease(state: &State, job: &Job, claim: Claim) -> Result<bool> {
+    let (is_manual, expected_last_started) = match claim {
+        Claim::Scheduled(last_started) => (false, last_started),
+        Claim::Manual => (true, None),
+    };
+    
+    let now = time::now();
+    let lease_expires = now + chrono::TimeDelta::from_std(job.timeout + LEASE_MARGIN)
+        .expect("Job timeout should be in range");
+    
+    let taken = sqlx::query!(
+        "INSERT INTO periodic_jobs (name, last_started, last_finished, last_error, lease_owner, lease_expires)
+            VALUES (?, ?, NULL, NULL, ?, ?)
+            ON CONFLICT (name) DO UPDATE SET
+                last_started = excluded.last_started,
+                last_finished = NULL,
+                last_error = NULL,
+                lease_owner = excluded.lease_owner,
+                lease_expires = excluded.lease_expires
+            WHERE (periodic_jobs.lease_expires IS NULL OR periodic_jobs.lease_expires <= ?)
+            AND (? OR periodic_jobs.last_started IS ?)",
+        job.name,
+        now,
+        state.instance_id,
+        lease_expires,
+        now,
+        is_manual,
+        expected_last_started,
+    )
+        .execute(&state.db)
+        .await?
+        .rows_affected() > 0;
+    
+    Ok(taken)
+}
+
+/// Releases this server process's lease on a job, and records the result of
+/// the run.
+async fn release_lease(state: &State, job: &Job, last_error: Option<String>) -> Result<()> {
+    let finished = time::now();
+    sqlx::query!(
+        "UPDATE periodic_jobs SET last_finished = ?, last_error = ?, lease_owner = NULL, lease_expires = NULL
+            WHERE name = ? AND lease_owner = ?",
+        finished,
+        last_error,
+        job.name,
+        state.instance_id,
+    )
+        .execute(&state.db)
+        .await?;
+    
+    Ok(())
+}
+
+/// Runs a job once, if no other server process is running it, and records
+/// when it ran and whether it succeeded. The job is cancelled if it takes too
+/// long. Failures of the job itself are logged and recorded, rather than
+/// returned. Returns `false` if the job didn't run because it couldn't take
+/// the lease.
+async fn run_job(state: &State, job: &Job, claim: Claim) -> Result<bool> {
+    if !job.local && !take_lease(state, job, claim).await? {
+        log::debug!("Job {} is already running, or has already run", job.name);
+        return Ok(false);
+    }
+    
+    let last_error = match timeout(job.timeout, (job.run)(state.clone())).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{e:?}")),
+        Err(_) => Some(format!("Timed out after {}s", job.timeout.as_secs())),
+    };
+    if let Some(e) = &last_error {
+        log::error!("Error in periodic job {}: {e}", job.name);
+    }
+    
+    if !job.local {
+        release_lease(state, job, last_error)
+            .await?;
+    }
+    
+    Ok(true)
+}
+
+/// The schedule and most recent run of a periodic job, as shown to admins.
+#[derive(serde::Serialize)]
+pub struct JobStatus {
+    pub name: &'static str,
+    pub schedule: String,
+    
+    #[serde(rename = "lastStarted")]
+    pub last_started: Option<time::DateTime>,
+    
+    /// When the most recent run finished, or `None` if it is still running.
+    #[serde(rename = "lastFinished")]
+    pub last_finished: Option<time::DateTime>,
+    
+    /// The error from the most recent run, if it failed.
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+    
+    /// The id of the server process running the job, if it is running.
+    #[serde(rename = "runningOn")]
+    pub running_on: Option<String>,
+    
+    #[serde(rename = "nextRun")]
+    pub next_run: time::DateTime,
+}
+
+impl JobStatus {
+    /// Fetches the status of every periodic job.
+    pub async fn get_all(state: &State) -> Result<Vec<Self>> {
+        let now = time::now();
+        let rows = sqlx::query!(
+            "SELECT name, last_started, last_finished, last_error,
+                    CASE WHEN lease_expires > ? THEN lease_owner END AS running_on
+                FROM periodic_jobs",
+            now,
+        )
+            .fetch_all(&state.db)
+            .await?;
+        
+        let statuses = registry(state)
+            .into_iter()
+            .map(|job| {
+                let row = rows.iter().find(|row| row.name == job.name);
+                let last_started = row.map(|row| row.last_started);
+                Self {
+                    name: job.name,
+                    schedule: job.schedule.describe(),
+                    last_started,
+                    last_finished: row.and_then(|row| row.last_finished),
+                    last_error: row.and_then(|row| row.last_error.clone()),
+                    running_on: row.and_then(|row| row.running_on.clone()),
+                    next_run: job.schedule.next_run(last_started, now).max(now),
+                }
+            })
+            .collect();
+        
+        Ok(statuses)
+    }
 }
 
 trait ReportIfError {
@@ -36,3 +429,144 @@ impl <T> ReportIfError for Result<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    
+    use super::{get_last_started, run_if_due, run_job, take_lease, time_until_due, Claim, Job, JobFuture, Schedule};
+    use crate::{
+        mailer::MemoryMailer,
+        model::time,
+        result::AppError,
+        state,
+    };
+    
+    fn at(hour: u32, minute: u32) -> time::DateTime {
+        chrono::NaiveDate::from_ymd_opt(2024, 1, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+    
+    #[test]
+    fn interval_runs_after_last_start() {
+        let schedule = Schedule::Every(Duration::from_secs(10 * 60));
+        assert_eq!(at(9, 30), schedule.next_run(None, at(9, 30)));
+        assert_eq!(at(9, 10), schedule.next_run(Some(at(9, 0)), at(9, 30)));
+        assert_eq!(at(9, 40), schedule.next_run(Some(at(9, 30)), at(9, 30)));
+    }
+    
+    #[test]
+    fn cron_runs_at_next_matching_time() {
+        let schedule = Schedule::cron(super::HOURLY);
+        // A new job waits for the next matching time
+        assert_eq!(at(10, 0), schedule.next_run(None, at(9, 30)));
+        // A run missed while the server was down is due immediately
+        assert_eq!(at(9, 0), schedule.next_run(Some(at(8, 0)), at(9, 30)));
+    }
+    
+    #[actix_web::test]
+    async fn runs_are_recorded() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let job = Job {
+            name: "test",
+            schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |_| -> JobFuture { Box::pin(async { Err(AppError::BAD_REQUEST) }) },
+        };
+        
+        // A job which has never run is due immediately
+        let last_started = get_last_started(&state, &job).await.unwrap();
+        assert_eq!(None, time_until_due(&job, last_started));
+        run_job(&state, &job, Claim::Scheduled(last_started)).await.unwrap();
+        
+        // The job isn't due again until its interval has passed
+        let last_started = get_last_started(&state, &job).await.unwrap();
+        assert!(time_until_due(&job, last_started).is_some());
+        
+        let row = sqlx::query!("SELECT last_finished, last_error FROM periodic_jobs WHERE name = 'test'")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert!(row.last_finished.is_some());
+        assert!(row.last_error.is_some());
+    }
+    
+    #[actix_web::test]
+    async fn slow_runs_time_out() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let job = Job {
+            name: "slow",
+            schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+            timeout: Duration::from_millis(10),
+            local: false,
+            run: |_| -> JobFuture {
+                Box::pin(async {
+                    actix_web::rt::time::sleep(Duration::from_secs(60)).await;
+                    Ok(())
+                })
+            },
+        };
+        
+        run_job(&state, &job, Claim::Manual).await.unwrap();
+        let last_error = sqlx::query_scalar!("SELECT last_error FROM periodic_jobs WHERE name = 'slow'")
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert!(last_error.is_some_and(|e| e.contains("Timed out")));
+    }
+    
+    #[actix_web::test]
+    async fn leases_stop_other_processes_running_jobs() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let job = Job {
+            name: "leased",
+            schedule: Schedule::Every(Duration::from_secs(60 * 60)),
+            timeout: Duration::from_secs(60),
+            local: false,
+            run: |_| -> JobFuture { Box::pin(async { Ok(()) }) },
+        };
+        
+        assert!(take_lease(&state, &job, Claim::Scheduled(None)).await.unwrap());
+        let last_started = get_last_started(&state, &job).await.unwrap();
+        
+        // Another process which saw the job was due can't run it again, and
+        // it can't be run manually while the lease is held
+        assert!(!take_lease(&state, &job, Claim::Scheduled(None)).await.unwrap());
+        assert!(!take_lease(&state, &job, Claim::Manual).await.unwrap());
+        
+        // An expired lease can be taken over, as if the process holding it
+        // stopped
+        sqlx::query!("UPDATE periodic_jobs SET lease_owner = 'other', lease_expires = ? WHERE name = 'leased'", last_started)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        assert!(take_lease(&state, &job, Claim::Scheduled(last_started)).await.unwrap());
+    }
+    
+    #[actix_web::test]
+    async fn leased_job_waits_for_its_interval() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let job = Job {
+            name: "busy",
+            schedule: Schedule::Every(Duration::from_secs(10)),
+            timeout: Duration::from_secs(5 * 60),
+            local: false,
+            run: |_| -> JobFuture { Box::pin(async { panic!("Job should not run while leased") }) },
+        };
+        
+        // Another process has been running the job for longer than its
+        // interval, so it is due again but still leased
+        assert!(take_lease(&state, &job, Claim::Manual).await.unwrap());
+        let long_ago = time::add_days(time::now(), -1.0);
+        sqlx::query!("UPDATE periodic_jobs SET lease_owner = 'other', last_started = ? WHERE name = 'busy'", long_ago)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        let delay = run_if_due(&state, &job, &mut None).await;
+        assert!(delay >= Duration::from_secs(9), "{delay:?}");
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::HttpRequest;
+
+use crate::{
+    config::Config,
+    result::{AppError, Result},
+    state::State,
+};
+
+/// Limits how often clients can attempt actions such as logging in, so that
+/// passwords can't be brute-forced and emails can't be sent in bulk. Each
+/// attempt is recorded against some keys, e.g. the client's IP address and
+/// the account being accessed; after a number of free attempts, the client
+/// must wait before each further attempt, and the wait doubles every time.
+/// 
+/// Attempts are only counted in memory, so they are forgotten when the
+/// server restarts.
+pub struct RateLimiter {
+    free_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+struct Attempts {
+    count: u32,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            free_attempts: cfg.rate_limit_free_attempts,
+            base_delay: Duration::from_secs(cfg.rate_limit_base_delay_secs),
+            max_delay: Duration::from_secs(cfg.rate_limit_max_delay_secs),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    /// Records an attempt against each of the keys, or returns an HTTP "429
+    /// Too Many Requests" error if another attempt isn't allowed yet for any
+    /// of them. The attempt is checked and recorded at once, before it is
+    /// made, so that concurrent attempts can't all pass the check.
+    pub fn reserve_attempt(&self, keys: &[String]) -> Result<()> {
+        self.reserve_attempt_at(keys, Instant::now())
+    }
+    
+    /// Takes back an attempt recorded by `reserve_attempt`, which turned out
+    /// not to count towards the limit.
+    pub fn release_attempt(&self, keys: &[String]) {
+        let mut attempts = self.lock();
+        for key in keys {
+            if let Some(a) = attempts.get_mut(key) {
+                a.count = a.count.saturating_sub(1);
+            }
+        }
+    }
+    
+    /// Forgets the attempts recorded against a key, e.g. after a successful
+    /// login to an account.
+    pub fn reset(&self, key: &str) {
+        self.lock().remove(key);
+    }
+    
+    /// Forgets keys whose last attempt was long enough ago that they no longer
+    /// limit anything. This function will be called periodically.
+    pub fn forget_stale(&self) {
+        let now = Instant::now();
+        self.lock()
+            .retain(|_, a| now.duration_since(a.last) < self.max_delay);
+    }
+    
+    fn reserve_attempt_at(&self, keys: &[String], now: Instant) -> Result<()> {
+        let mut attempts = self.lock();
+        
+        let wait = keys.iter()
+            .filter_map(|key| attempts.get(key))
+            .map(|a| (a.last + self.delay_after(a.count)).saturating_duration_since(now))
+            .max()
+            .unwrap_or_default();
+        
+        if !wait.is_zero() {
+            return Err(AppError::TooManyRequests(wait));
+        }
+        
+        for key in keys {
+            let a = attempts.entry(key.clone())
+                .or_insert(Attempts {count: 0, last: now});
+            
+            // Start counting again if the key hasn't been used for a while
+            if now.duration_since(a.last) >= self.max_delay {
+                a.count = 0;
+            }
+            a.count += 1;
+            a.last = now;
+        }
+        
+        Ok(())
+    }
+    
+    /// Computes how long a client must wait after the given number of
+    /// attempts, before another attempt is allowed.
+    fn delay_after(&self, count: u32) -> Duration {
+        let Some(excess) = count.checked_sub(self.free_attempts) else {
+            return Duration::ZERO;
+        };
+        
+        // Avoid overflow; the maximum delay is reached long before this
+        let factor = 1u32 << excess.min(20);
+        (self.base_delay * factor).min(self.max_delay)
+    }
+    
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Attempts>> {
+        self.attempts.lock()
+            .expect("Rate limiter lock should not be poisoned")
+    }
+}
+
+/// Builds the keys for a client attempting an action on an account: one for
+/// the client's IP address, and one for the account. Limiting by IP address
+/// slows down attacks on many accounts, and limiting by account slows down
+/// attacks on one account from many addresses.
+pub fn keys_for(action: &str, request: &HttpRequest, account: &str) -> [String; 2] {
+    let state: &State = request.app_data::<State>()
+        .expect("State should be available from app data");
+    let ip_address = state.cfg.client_ip(request)
+        .unwrap_or_else(|| "unknown".to_string());
+    
+    [
+        format!("{action}:ip:{ip_address}"),
+        // Email addresses are case-insensitive
+        format!("{action}:account:{}", account.trim().to_lowercase()),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+    
+    use super::{Attempts, RateLimiter};
+    
+    fn limiter() -> RateLimiter {
+        RateLimiter {
+            free_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+    
+    #[test]
+    fn backoff_doubles_up_to_maximum() {
+        let limiter = limiter();
+        let delays: Vec<u64> = (0..12)
+            .map(|count| limiter.delay_after(count).as_secs())
+            .collect();
+        
+        assert_eq!(vec![0, 0, 0, 1, 2, 4, 8, 16, 32, 60, 60, 60], delays);
+    }
+    
+    #[test]
+    fn blocks_after_free_attempts() {
+        let limiter = limiter();
+        let keys = ["login:ip:1.2.3.4".to_string(), "login:account:a@b.c".to_string()];
+        let start = Instant::now();
+        
+        for _ in 0..3 {
+            assert!(limiter.reserve_attempt_at(&keys, start).is_ok());
+        }
+        assert!(limiter.reserve_attempt_at(&keys, start).is_err());
+        
+        // Another key sharing the same account is also blocked
+        let other_ip = ["login:ip:5.6.7.8".to_string(), keys[1].clone()];
+        assert!(limiter.reserve_attempt_at(&other_ip, start).is_err());
+        
+        // The blocked attempts weren't recorded
+        assert!(limiter.reserve_attempt_at(&keys, start + Duration::from_secs(1)).is_ok());
+        
+        limiter.reset(&keys[1]);
+        assert!(limiter.reserve_attempt_at(&other_ip, start).is_ok());
+    }
+    
+    #[test]
+    fn released_attempts_do_not_count() {
+        let limiter = limiter();
+        let keys = ["login:ip:1.2.3.4".to_string()];
+        let start = Instant::now();
+        
+        for _ in 0..10 {
+            assert!(limiter.reserve_attempt_at(&keys, start).is_ok());
+            limiter.release_attempt(&keys);
+        }
+    }
+    
+    #[test]
+    fn forgets_after_maximum_delay() {
+        let limiter = limiter();
+        let keys = ["send_mail:ip:1.2.3.4".to_string()];
+        let start = Instant::now();
+        
+        limiter.lock().insert(keys[0].clone(), Attempts {count: 10, last: start});
+        assert!(limiter.reserve_attempt_at(&keys, start).is_err());
+        
+        // The count starts again, so more attempts are free
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.reserve_attempt_at(&keys, later).is_ok());
+        assert!(limiter.reserve_attempt_at(&keys, later).is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{TimeDelta, Timelike};
+
+use crate::{
+    auth::MailingList,
+    model::{time, Subscriber, UserPreferences},
+    result::Result,
+    state::State,
+};
+
+/// Sends a reminder email to each user who has opted in, if it is past their
+/// chosen time of day, they have reviews due, and they haven't studied yet
+/// today. Each user gets at most one reminder per day, in their local time,
+/// even if this job runs more than once. This function will be called
+/// periodically.
+pub async fn send_due_reminders(state: &State) -> Result<()> {
+    let subscribers = UserPreferences::get_subscribers(state, MailingList::Reminders)
+        .await?;
+    
+    let now = time::now();
+    for subscriber in subscribers {
+        // One user's failure shouldn't hold up everyone else's reminders
+        let user_id = subscriber.user.id;
+        if let Err(e) = send_reminder_if_due(state, subscriber, now).await {
+            log::error!("Failed to send a reminder to user #{user_id}: {e:?}");
+        }
+    }
+    
+    Ok(())
+}
+
+async fn send_reminder_if_due(state: &State, subscriber: Subscriber, now: time::DateTime) -> Result<()> {
+    let Subscriber {user, prefs, last_sent_date} = subscriber;
+    let local_now = prefs.local_time(now);
+    let local_date = local_now.date();
+    
+    let minutes_after_midnight = i64::from(local_now.hour() * 60 + local_now.minute());
+    if minutes_after_midnight < prefs.reminder_time || last_sent_date.is_some_and(|d| d >= local_date) {
+        return Ok(());
+    }
+    
+    // The start of the user's day, in UTC
+    let start_of_day = time::start_of_day(local_now) - TimeDelta::minutes(prefs.utc_offset_minutes);
+    let counts = sqlx::query!(
+        r#"SELECT
+            (SELECT COUNT(1) FROM user_tsumego_stats
+                WHERE user_id = ? AND review_due <= ?
+            ) AS "due_today!: i64",
+            (SELECT COUNT(1) FROM user_tsumego_reviews
+                WHERE user_id = ? AND review_date >= ?
+            ) AS "done_today!: i64""#,
+        user.id,
+        now,
+        user.id,
+        start_of_day,
+    )
+        .fetch_one(&state.db)
+        .await?;
+    
+    if counts.due_today == 0 || counts.done_today > 0 {
+        return Ok(());
+    }
+    
+    // Claim today's reminder before sending it, so that if this job runs
+    // twice at once, only one of them sends it
+    let claimed = sqlx::query!(
+        "UPDATE user_preferences SET last_reminder_date = ?
+            WHERE user_id = ? AND (last_reminder_date IS NULL OR last_reminder_date < ?)",
+        local_date,
+        user.id,
+        local_date,
+    )
+        .execute(&state.db)
+        .await?
+        .rows_affected() > 0;
+    
+    if !claimed {
+        return Ok(());
+    }
+    
+    let result = crate::auth::send_daily_reminder(state, &user, counts.due_today)
+        .await;
+    if result.is_err() {
+        // Release the claim, so the reminder is sent next time this job runs
+        sqlx::query!(
+            "UPDATE user_preferences SET last_reminder_date = ? WHERE user_id = ?",
+            last_sent_date,
+            user.id,
+        )
+            .execute(&state.db)
+            .await?;
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, middleware, test, App};
+    
+    use super::send_due_reminders;
+    use crate::{
+        auth::{verify_unsubscribe_token, MailingList},
+        mailer::MemoryMailer,
+        model::{time, UserPreferences},
+        state::{self, insert_test_user, State},
+    };
+    
+    /// Sets up a user who wants reminders at any time of day, and has one
+    /// review due.
+    async fn user_with_review_due(state: &State) -> i64 {
+        let user_id = insert_test_user(state, "alice@example.com")
+            .await
+            .id;
+        
+        let prefs = UserPreferences {
+            utc_offset_minutes: 0,
+            reminders_enabled: true,
+            reminder_time: 0,
+            digest_enabled: false,
+        };
+        prefs.save(state, user_id)
+            .await
+            .unwrap();
+        
+        let yesterday = time::add_days(time::now(), -1.0);
+        sqlx::query!("INSERT INTO tsumego (id, name, board, tree) VALUES (1, 'test', '', '')")
+            .execute(&state.db)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO user_tsumego_stats (user_id, tsumego_id, last_review_date, review_due, num_reviews, streak_length, interval, e_factor)
+                VALUES (?, 1, ?, ?, 1, 1, 1.0, 2.5)",
+            user_id,
+            yesterday,
+            yesterday,
+        )
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        user_id
+    }
+    
+    #[actix_web::test]
+    async fn reminder_is_sent_once_per_day() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user_id = user_with_review_due(&state).await;
+        
+        send_due_reminders(&state).await.unwrap();
+        send_due_reminders(&state).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        
+        let sent = mailer.sent();
+        assert_eq!(1, sent.len());
+        
+        let token = sent[0].body
+            .split_whitespace()
+            .find_map(|word| word.split_once("unsubscribe/reminders?token="))
+            .map(|(_, token)| token)
+            .expect("Reminder should have an unsubscribe link");
+        assert_eq!(Some(user_id), verify_unsubscribe_token(&state, MailingList::Reminders, token));
+        assert_eq!(None, verify_unsubscribe_token(&state, MailingList::Reminders, &format!("{}{token}", user_id + 1)));
+        assert!(sent[0].list_unsubscribe.as_deref().is_some_and(|url| url.ends_with(token)));
+    }
+    
+    #[actix_web::test]
+    async fn unsubscribe_link_asks_for_confirmation() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user_id = user_with_review_due(&state).await;
+        
+        send_due_reminders(&state).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        
+        let url = mailer.sent()[0].list_unsubscribe.clone()
+            .expect("Reminder should have a List-Unsubscribe URL");
+        let path = url.strip_prefix(state.cfg.base_url.as_ref())
+            .expect("Link should be to this site");
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(crate::middleware::csrf_middleware))
+        ).await;
+        
+        // Following the link doesn't unsubscribe the user by itself
+        let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/{path}")).to_request())
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let prefs = UserPreferences::get_for_user(&mut state.db.acquire().await.unwrap(), user_id).await.unwrap();
+        assert!(prefs.reminders_enabled);
+        
+        // A one-click unsubscribe request from a mail client has no Referer
+        let request = test::TestRequest::post()
+            .uri(&format!("/{path}"))
+            .set_form([("List-Unsubscribe", "One-Click")])
+            .to_request();
+        let response = test::call_service(&app, request)
+            .await;
+        assert_eq!(StatusCode::OK, response.status());
+        let prefs = UserPreferences::get_for_user(&mut state.db.acquire().await.unwrap(), user_id).await.unwrap();
+        assert!(!prefs.reminders_enabled);
+    }
+    
+    #[actix_web::test]
+    async fn no_reminder_after_studying_today() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user_id = user_with_review_due(&state).await;
+        
+        let now = time::now();
+        sqlx::query!(
+            "INSERT INTO user_tsumego_reviews (user_id, tsumego_id, review_date, grade) VALUES (?, 1, ?, 3)",
+            user_id,
+            now,
+        )
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        send_due_reminders(&state).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        assert!(mailer.sent().is_empty());
+    }
+    
+    #[actix_web::test]
+    async fn failed_reminder_is_retried() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        user_with_review_due(&state).await;
+        
+        // Another user also has a review due
+        let bob_id = insert_test_user(&state, "bob@example.com")
+            .await
+            .id;
+        UserPreferences {reminders_enabled: true, reminder_time: 0, ..UserPreferences::default()}
+            .save(&state, bob_id)
+            .await
+            .unwrap();
+        let yesterday = time::add_days(time::now(), -1.0);
+        sqlx::query!(
+            "INSERT INTO user_tsumego_stats (user_id, tsumego_id, last_review_date, review_due, num_reviews, streak_length, interval, e_factor)
+                VALUES (?, 1, ?, ?, 1, 1, 1.0, 2.5)",
+            bob_id,
+            yesterday,
+            yesterday,
+        )
+            .execute(&state.db)
+            .await
+            .unwrap();
+        
+        // Queueing Alice's reminder fails, but Bob's is still sent
+        sqlx::query("CREATE TEMP TRIGGER fail_alice BEFORE INSERT ON outbound_mail
+                WHEN NEW.recipient = 'alice@example.com'
+                BEGIN SELECT RAISE(ABORT, 'Simulated failure'); END")
+            .execute(&state.db)
+            .await
+            .unwrap();
+        send_due_reminders(&state).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        assert!(mailer.last_link_to("alice@example.com").is_none());
+        assert!(mailer.last_link_to("bob@example.com").is_some());
+        
+        // Alice's reminder wasn't claimed, so it is sent next time
+        sqlx::query("DROP TRIGGER fail_alice")
+            .execute(&state.db)
+            .await
+            .unwrap();
+        send_due_reminders(&state).await.unwrap();
+        crate::mail_queue::send_due(&state).await.unwrap();
+        assert!(mailer.last_link_to("alice@example.com").is_some());
+        assert_eq!(2, mailer.sent().len());
+    }
+}
@@ -1,6 +1,6 @@
 use actix_web::{
     body::{BoxBody, MessageBody},
-    http::StatusCode,
+    http::{header, StatusCode},
     HttpResponse,
     ResponseError,
 };
@@ -10,15 +10,23 @@ pub type Result<T, E = AppError> = std::result::Result<T, E>;
 #[derive(Debug)]
 pub enum AppError {
     Status(StatusCode),
+    /// The client has made too many attempts, and must wait for the given
+    /// duration before trying again.
+    TooManyRequests(std::time::Duration),
     Auth(authlogic::Error),
-    Mail(crate::auth::MailError),
+    Mail(crate::mailer::MailError),
+    /// An error from a request to another server, e.g. an OpenID Connect
+    /// provider.
+    Http(reqwest::Error),
     Io(std::io::Error),
+    Template(minijinja::Error),
     Sql(sqlx::Error),
 }
 
 impl AppError {
     pub const BAD_REQUEST: AppError = AppError::Status(StatusCode::BAD_REQUEST);
     pub const UNAUTHORIZED: AppError = AppError::Status(StatusCode::UNAUTHORIZED);
+    pub const FORBIDDEN: AppError = AppError::Status(StatusCode::FORBIDDEN);
     pub const NOT_FOUND: AppError = AppError::Status(StatusCode::NOT_FOUND);
 
     pub fn http_reason(&self) -> &str {
@@ -40,6 +48,7 @@ impl ResponseError for AppError {
         match self {
             AppError::Auth(err) => err.status_code(),
             AppError::Status(code) => *code,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -57,8 +66,15 @@ impl ResponseError for AppError {
             log::error!("{}: {self:?}", self.http_reason());
         }
 
-        HttpResponse::new(self.status_code())
-            .set_body(MessageBody::boxed(reason))
+        let mut response = HttpResponse::new(self.status_code());
+        if let AppError::TooManyRequests(wait) = self {
+            // Round up, so the client doesn't retry too early
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            response.headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        
+        response.set_body(MessageBody::boxed(reason))
     }
 }
 
@@ -74,12 +90,24 @@ impl From<authlogic::Error> for AppError {
     }
 }
 
-impl From<crate::auth::MailError> for AppError {
-    fn from(err: crate::auth::MailError) -> Self {
+impl From<crate::mailer::MailError> for AppError {
+    fn from(err: crate::mailer::MailError) -> Self {
         AppError::Mail(err)
     }
 }
 
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        AppError::Http(err)
+    }
+}
+
+impl From<minijinja::Error> for AppError {
+    fn from(err: minijinja::Error) -> Self {
+        AppError::Template(err)
+    }
+}
+
 impl From<sqlx::Error> for AppError {
     fn from(err: sqlx::Error) -> Self {
         AppError::Sql(err)
@@ -96,18 +124,18 @@ impl <T> OrAppError<T> for Option<T> {
     /// Converts this `Option` into a `Result`, replacing `None` with an HTTP
     /// "400 Bad Request" error.
     fn or_400_bad_request(self) -> Result<T> {
-        self.map_or(Err(AppError::BAD_REQUEST), Ok)
+        self.ok_or(AppError::BAD_REQUEST)
     }
     
     /// Converts this `Option` into a `Result`, replacing `None` with an HTTP
     /// "401 Unauthorized" error.
     fn or_401_unauthorised(self) -> Result<T> {
-        self.map_or(Err(AppError::UNAUTHORIZED), Ok)
+        self.ok_or(AppError::UNAUTHORIZED)
     }
     
     /// Converts this `Option` into a `Result`, replacing `None` with an HTTP
     /// "404 Not Found" error.
     fn or_404_not_found(self) -> Result<T> {
-        self.map_or(Err(AppError::NOT_FOUND), Ok)
+        self.ok_or(AppError::NOT_FOUND)
     }
 }
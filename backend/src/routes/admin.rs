@@ -0,0 +1,146 @@
+use actix_web::{
+    delete,
+    get,
+    post,
+    web::{Json, Path, ServiceConfig},
+    FromRequest,
+    HttpResponse,
+    Responder,
+};
+
+use authlogic::AppDb;
+
+use crate::{
+    mail_queue::QueuedMail,
+    periodic_jobs::{self, JobStatus},
+    model::User,
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// Declares routes for administrative actions. These routes can only be used
+/// by admin users.
+pub fn declare_routes(conf: &mut ServiceConfig) {
+    conf.service(delete_user)
+        .service(suspend_user)
+        .service(unsuspend_user)
+        .service(list_mail_queue)
+        .service(retry_mail)
+        .service(list_jobs)
+        .service(run_job);
+}
+
+/// An authenticated user who is an admin.
+struct Admin(User);
+
+impl FromRequest for Admin {
+    type Error = AppError;
+    type Future = super::LocalFuture<Self>;
+    
+    fn from_request(req: &actix_web::HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let user = User::from_request(req, payload);
+        Box::pin(async move {
+            let user = user.await?;
+            if user.is_admin {
+                Ok(Admin(user))
+            } else {
+                log::info!("User #{} is not an admin", user.id);
+                Err(AppError::FORBIDDEN)
+            }
+        })
+    }
+}
+
+#[delete("/api/admin/user/{id}")]
+async fn delete_user(state: State, admin: Admin, id: Path<i64>) -> Result<impl Responder> {
+    // Unverified users can be deleted too, so don't use `User::get_by_id`
+    let user = state.get_user_data_by_id(*id)
+        .await?
+        .or_404_not_found()?
+        .user;
+    
+    log::info!("Admin #{} is deleting user #{}", admin.0.id, user.id);
+    state.delete_user(user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(serde::Deserialize)]
+struct SuspendForm {
+    reason: String,
+}
+
+#[post("/api/admin/user/{id}/suspend")]
+async fn suspend_user(state: State, admin: Admin, id: Path<i64>, form: Json<SuspendForm>) -> Result<impl Responder> {
+    // Admins can't lock themselves out
+    if form.reason.is_empty() || *id == admin.0.id {
+        return Err(AppError::BAD_REQUEST);
+    }
+    
+    let user = state.get_user_data_by_id(*id)
+        .await?
+        .or_404_not_found()?
+        .user;
+    
+    log::info!("Admin #{} is suspending user #{}", admin.0.id, user.id);
+    crate::auth::suspend_user(&state, &user, &form.reason)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/api/admin/user/{id}/unsuspend")]
+async fn unsuspend_user(state: State, admin: Admin, id: Path<i64>) -> Result<impl Responder> {
+    let user = state.get_user_data_by_id(*id)
+        .await?
+        .or_404_not_found()?
+        .user;
+    
+    log::info!("Admin #{} is unsuspending user #{}", admin.0.id, user.id);
+    crate::auth::unsuspend_user(&state, &user)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+/// Lists the emails which are waiting to be sent, or which failed to send.
+#[get("/api/admin/mail_queue")]
+async fn list_mail_queue(state: State, _admin: Admin) -> Result<impl Responder> {
+    let queued = QueuedMail::get_all(&state)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(queued))
+}
+
+#[post("/api/admin/mail_queue/{id}/retry")]
+async fn retry_mail(state: State, admin: Admin, id: Path<i64>) -> Result<impl Responder> {
+    QueuedMail::retry(&state, *id)
+        .await?
+        .then_some(())
+        .or_404_not_found()?;
+    
+    log::info!("Admin #{} is retrying email #{}", admin.0.id, *id);
+    Ok(HttpResponse::Ok())
+}
+
+/// Lists the periodic jobs, with their schedules and most recent runs.
+#[get("/api/admin/jobs")]
+async fn list_jobs(state: State, _admin: Admin) -> Result<impl Responder> {
+    let jobs = JobStatus::get_all(&state)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+/// Runs a periodic job now. The job runs in the background, so the response
+/// is sent before it finishes.
+#[post("/api/admin/jobs/{name}/run")]
+async fn run_job(state: State, admin: Admin, name: Path<String>) -> Result<impl Responder> {
+    periodic_jobs::trigger(&state, &name)
+        .then_some(())
+        .or_404_not_found()?;
+    
+    log::info!("Admin #{} triggered job {}", admin.0.id, *name);
+    Ok(HttpResponse::Accepted())
+}
@@ -1,7 +1,8 @@
 use actix_web::{
     get,
     post,
-    web::{Json, Query, Redirect, ServiceConfig},
+    web::{Json, Path, Query, Redirect, ServiceConfig},
+    HttpMessage,
     HttpRequest,
     HttpResponse,
     Responder,
@@ -10,9 +11,11 @@ use actix_web::{
 use authlogic::Secret;
 
 use crate::{
-    auth::MaybeAuth,
-    model::{User, UserDetails},
-    result::Result,
+    auth::{AccountUpdateError, Auth, CustomChallenge, MaybeAuth},
+    middleware::ClearSessionCookie,
+    model::{Session, User, UserDetails},
+    rate_limit,
+    result::{AppError, OrAppError, Result},
     state::State,
 };
 
@@ -20,9 +23,23 @@ use crate::{
 pub fn declare_routes(conf: &mut ServiceConfig) {
     conf.service(register_account)
         .service(verify_challenge)
+        .service(confirm_challenge)
         .service(login)
+        .service(login_two_factor)
         .service(logout)
-        .service(who_am_i);
+        .service(who_am_i)
+        .service(forgot_password)
+        .service(email_login)
+        .service(change_password)
+        .service(update_profile)
+        .service(change_email)
+        .service(enrol_two_factor)
+        .service(enable_two_factor)
+        .service(disable_two_factor)
+        .service(list_sessions)
+        .service(revoke_session)
+        .service(revoke_other_sessions)
+        .service(delete_account);
 }
 
 #[derive(serde::Deserialize)]
@@ -34,13 +51,20 @@ struct RegisterForm {
 }
 
 #[post("/api/register")]
-async fn register_account(state: State, form: Json<RegisterForm>) -> Result<impl Responder> {
+async fn register_account(state: State, request: HttpRequest, form: Json<RegisterForm>) -> Result<impl Responder> {
     let form = form.into_inner();
+    
+    // Every registration sends an email, so every attempt counts
+    let keys = rate_limit::keys_for("send_mail", &request, &form.email);
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
     let user = User {
         id: -1,
         email: form.email,
         display_name: form.display_name,
         is_admin: false,
+        require_password_change: false,
+        two_factor_pending: false,
     };
     
     let outcome = crate::auth::register(&state, user, form.password)
@@ -48,6 +72,8 @@ async fn register_account(state: State, form: Json<RegisterForm>) -> Result<impl
     Ok(HttpResponse::Ok().json(outcome))
 }
 
+const DELETE_ACCOUNT_PAGE: &str = include_str!("../../templates/delete_account.html");
+
 #[derive(serde::Deserialize)]
 struct ChallengeForm {
     code: Secret,
@@ -60,27 +86,76 @@ pub fn confirmation_link(state: &State, code: Secret) -> String {
     format!("{base_url}verify?code={}", code.expose())
 }
 
+/// Completes a challenge from a link in an email. Challenges which can't be
+/// undone, i.e. deleting an account, aren't completed straight away, since
+/// some mail scanners follow every link in an email; instead, the user is
+/// asked to confirm, and the challenge is completed by `confirm_challenge`.
 #[get("/verify")]
 async fn verify_challenge(state: State, request: HttpRequest, query: Query<ChallengeForm>) -> Result<impl Responder> {
-    use authlogic::mail::{Challenge, complete_challenge};
+    use authlogic::mail::Challenge;
     
     let code = query.into_inner().code;
-    let (_, challenge) = complete_challenge(&state, code, &request)
+    let challenge = crate::auth::peek_challenge(&state, &code)
+        .await?
+        .ok_or(authlogic::Error::IncorrectChallengeCode)?;
+    
+    if let Challenge::Custom(CustomChallenge::DeleteAccount) = challenge {
+        // The form on this page posts back to the same URL
+        let response = HttpResponse::Ok()
+            .content_type("text/html")
+            .body(DELETE_ACCOUNT_PAGE);
+        return Ok(response);
+    }
+    
+    complete_challenge(&state, &request, code)
+        .await
+}
+
+/// Completes a challenge after the user confirms it, from the page shown by
+/// `verify_challenge`.
+#[post("/verify")]
+async fn confirm_challenge(state: State, request: HttpRequest, query: Query<ChallengeForm>) -> Result<impl Responder> {
+    complete_challenge(&state, &request, query.into_inner().code)
+        .await
+}
+
+async fn complete_challenge(state: &State, request: &HttpRequest, code: Secret) -> Result<HttpResponse> {
+    use authlogic::mail::Challenge;
+    
+    let (user, challenge) = authlogic::mail::complete_challenge(state, code, request)
         .await?;
     
     let response_body = match challenge {
         Challenge::LogIn |
-        Challenge::ResetPassword => {
+        Challenge::ResetPassword |
+        Challenge::Custom(CustomChallenge::OidcLogin {..}) => {
             let response = Redirect::to("/")
                 .temporary()
-                .respond_to(&request)
+                .respond_to(request)
                 .map_into_boxed_body();
             return Ok(response);
         },
         Challenge::VerifyNewUser => {
             std::fs::read_to_string("templates/account_verified.html")?
         },
-        Challenge::Custom(c) => c.never_happens(),
+        Challenge::Custom(CustomChallenge::DeleteAccount) => {
+            use authlogic::AppDb;
+            state.delete_user(user.id)
+                .await?;
+            
+            // Completing the challenge began a session for the user, which
+            // no longer exists
+            request.extensions_mut().insert(ClearSessionCookie);
+            std::fs::read_to_string("templates/account_deleted.html")?
+        },
+        Challenge::Custom(CustomChallenge::ChangeEmail {new_email}) => {
+            let template_path = if crate::auth::complete_email_change(state, user, new_email).await? {
+                "templates/email_changed.html"
+            } else {
+                "templates/email_change_failed.html"
+            };
+            std::fs::read_to_string(template_path)?
+        },
     };
     
     // Construct a response manually; using `actix_files` would send cache
@@ -101,7 +176,42 @@ struct LoginForm {
 #[post("/api/login")]
 async fn login(state: State, request: HttpRequest, form: Json<LoginForm>) -> Result<impl Responder> {
     let form = form.into_inner();
-    let user = authlogic::login(&state, &form.email, form.password, &request)
+    
+    let keys = rate_limit::keys_for("login", &request, &form.email);
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
+    let result = authlogic::login(&state, &form.email, form.password, &request)
+        .await;
+    
+    // Only failed attempts count towards the limit. A successful login means
+    // the account isn't being attacked, but the IP address might still be
+    // attacking other accounts.
+    match &result {
+        Err(AppError::Auth(authlogic::Error::IncorrectPassword | authlogic::Error::NoSuchUser)) => {},
+        Ok(_) => {
+            state.rate_limiter.release_attempt(&keys[..1]);
+            state.rate_limiter.reset(&keys[1]);
+        },
+        Err(_) => state.rate_limiter.release_attempt(&keys),
+    }
+    let user = result?;
+    
+    let user_details = UserDetails::get_for_user(&state, user)
+        .await?;
+    Ok(HttpResponse::Ok().json(user_details))
+}
+
+#[derive(serde::Deserialize)]
+struct TwoFactorForm {
+    code: String,
+}
+
+/// Completes logging in for a user with two-factor authentication enabled.
+/// The first step is `/api/login`, or following an emailed login link, after
+/// which the user has a session with `twoFactorPending` set.
+#[post("/api/login/two_factor")]
+async fn login_two_factor(state: State, request: HttpRequest, auth: Auth, form: Json<TwoFactorForm>) -> Result<impl Responder> {
+    let user = crate::auth::complete_two_factor_login(&state, &request, auth, &form.code)
         .await?;
     
     let user_details = UserDetails::get_for_user(&state, user)
@@ -130,3 +240,178 @@ async fn who_am_i(state: State, auth: MaybeAuth) -> Result<impl Responder> {
     
     Ok(HttpResponse::Ok().json(user_details))
 }
+
+#[derive(serde::Deserialize)]
+struct EmailForm {
+    email: String,
+}
+
+/// Sends a password reset link to the given address, if it belongs to a
+/// verified user. The response is the same whether or not it does, so this
+/// route can't be used to find out which email addresses are registered.
+#[post("/api/forgot_password")]
+async fn forgot_password(state: State, request: HttpRequest, form: Json<EmailForm>) -> Result<impl Responder> {
+    let keys = rate_limit::keys_for("send_mail", &request, &form.email);
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
+    crate::auth::request_password_reset(&state, &form.email)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+/// Sends a one-time login link to the given address, if it belongs to a
+/// verified user. As for `forgot_password`, the response doesn't reveal
+/// whether the address is registered.
+#[post("/api/email_login")]
+async fn email_login(state: State, request: HttpRequest, form: Json<EmailForm>) -> Result<impl Responder> {
+    let keys = rate_limit::keys_for("send_mail", &request, &form.email);
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
+    crate::auth::request_login_link(&state, &form.email)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(serde::Deserialize)]
+struct ChangePasswordForm {
+    /// The user's current password. This is not needed if the user has no
+    /// password, e.g. after completing a password reset challenge.
+    #[serde(rename = "oldPassword", default)]
+    old_password: Option<Secret>,
+    #[serde(rename = "newPassword")]
+    new_password: Secret,
+    #[serde(rename = "keepOtherSessions", default)]
+    keep_other_sessions: bool,
+}
+
+#[post("/api/change_password")]
+async fn change_password(state: State, auth: Auth, form: Json<ChangePasswordForm>) -> Result<impl Responder> {
+    crate::auth::require_two_factor_complete(&auth)?;
+    
+    let form = form.into_inner();
+    let outcome = crate::auth::change_password(&state, auth, form.old_password, form.new_password, form.keep_other_sessions)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateProfileForm {
+    #[serde(rename = "displayName")]
+    display_name: String,
+}
+
+#[post("/api/update_profile")]
+async fn update_profile(state: State, user: User, form: Json<UpdateProfileForm>) -> Result<impl Responder> {
+    let outcome = crate::auth::update_profile(&state, &user, &form.display_name)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[derive(serde::Deserialize)]
+struct ChangeEmailForm {
+    /// The user's current password. This is not needed if the user has no
+    /// password.
+    #[serde(default)]
+    password: Option<Secret>,
+    #[serde(rename = "newEmail")]
+    new_email: String,
+}
+
+#[post("/api/change_email")]
+async fn change_email(state: State, request: HttpRequest, user: User, form: Json<ChangeEmailForm>) -> Result<impl Responder> {
+    let form = form.into_inner();
+    
+    // The user's password is checked here, so guesses count towards the same
+    // limit as logging in
+    let keys = rate_limit::keys_for("login", &request, &user.email);
+    state.rate_limiter.reserve_attempt(&keys)?;
+    
+    let result = crate::auth::request_email_change(&state, &user, form.password, form.new_email)
+        .await;
+    
+    if !result.as_ref().is_ok_and(|o| matches!(o.error, Some(AccountUpdateError::IncorrectPassword))) {
+        state.rate_limiter.release_attempt(&keys);
+    }
+    let outcome = result?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+/// Generates a new secret for the user's authenticator app. Two-factor
+/// authentication isn't enabled until the user gives a code generated from it.
+#[post("/api/two_factor/enrol")]
+async fn enrol_two_factor(state: State, user: User) -> Result<impl Responder> {
+    let enrolment = crate::auth::begin_two_factor_enrolment(&state, &user)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(enrolment))
+}
+
+#[post("/api/two_factor/enable")]
+async fn enable_two_factor(state: State, user: User, form: Json<TwoFactorForm>) -> Result<impl Responder> {
+    let outcome = crate::auth::enable_two_factor(&state, &user, &form.code)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[post("/api/two_factor/disable")]
+async fn disable_two_factor(state: State, user: User, form: Json<TwoFactorForm>) -> Result<impl Responder> {
+    let outcome = crate::auth::disable_two_factor(&state, &user, &form.code)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+/// Sends the user an email with a link to confirm that they want to delete
+/// their account. The account is only deleted once the link is followed.
+#[post("/api/delete_account")]
+async fn delete_account(state: State, user: User) -> Result<impl Responder> {
+    let challenge = CustomChallenge::DeleteAccount;
+    authlogic::mail::issue_custom_challenge(&state, &user, challenge)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/api/sessions")]
+async fn list_sessions(state: State, auth: Auth) -> Result<impl Responder> {
+    crate::auth::require_two_factor_complete(&auth)?;
+    
+    let sessions = Session::get_all_for_user(&state, auth.user.id, auth.session_id)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+#[post("/api/sessions/{id}/revoke")]
+async fn revoke_session(state: State, request: HttpRequest, auth: Auth, id: Path<i64>) -> Result<impl Responder> {
+    crate::auth::require_two_factor_complete(&auth)?;
+    
+    if *id == auth.session_id {
+        // Revoking the current session is the same as logging out
+        auth.logout(&state, &request)
+            .await?;
+    } else {
+        Session::revoke(&state, auth.user.id, *id)
+            .await?
+            .then_some(())
+            .or_404_not_found()?;
+    }
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/api/sessions/revoke_others")]
+async fn revoke_other_sessions(state: State, auth: Auth) -> Result<impl Responder> {
+    crate::auth::require_two_factor_complete(&auth)?;
+    
+    Session::revoke_all_others(&state, auth.user.id, auth.session_id)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
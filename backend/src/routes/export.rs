@@ -0,0 +1,218 @@
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, DispositionParam, DispositionType},
+    web::{self, Bytes, Json, JsonConfig, PayloadConfig, ServiceConfig},
+    HttpResponse,
+    Responder,
+};
+use futures_util::{stream, StreamExt};
+
+use crate::{
+    model::{reviews_csv, stats_csv, AnkiRevlogEntry, ArchiveFile, ImportedReview, User, UserExport, TAR_END},
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// The largest request body accepted when importing. A long review history
+/// is much larger than actix-web's default limits of 2MB for JSON and 256kB
+/// for other bodies, so the import routes set their own limits.
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Declares routes for exporting and importing a user's data.
+pub fn declare_routes(conf: &mut ServiceConfig) {
+    let json_config = JsonConfig::default().limit(MAX_IMPORT_BYTES);
+    
+    conf.service(export_archive)
+        .service(export_stats_csv)
+        .service(export_reviews_csv)
+        .service(web::resource("/api/import")
+            .app_data(json_config.clone())
+            .route(web::post().to(import_json)))
+        .service(web::resource("/api/import/anki")
+            .app_data(json_config)
+            .route(web::post().to(import_anki)))
+        .service(web::resource("/api/import/csv")
+            .app_data(PayloadConfig::default().limit(MAX_IMPORT_BYTES))
+            .route(web::post().to(import_csv)));
+}
+
+/// Builds a `Content-Disposition` header, so that browsers download the
+/// response as a file with the given name.
+fn attachment(filename: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.to_string())],
+    }
+}
+
+/// Downloads everything stored about the user, as a tar archive of JSON and
+/// CSV files; see `docs/export_format.md`. The user's data is read once, and
+/// each file in the archive is built from it and sent in turn, so only one
+/// file's contents are held in memory at a time besides the data itself.
+#[get("/api/export")]
+async fn export_archive(state: State, user: User) -> Result<impl Responder> {
+    let export = UserExport::get_for_user(&state, &user)
+        .await?;
+    
+    let files = stream::iter(ArchiveFile::ALL)
+        .map(move |file| file.to_tar_entry(&export).map(Bytes::from))
+        .chain(stream::once(async { Ok(Bytes::from_static(&TAR_END)) }))
+        .map(|entry| entry.map_err(|e| {
+            // The response has already begun, so the client will only see
+            // that the archive is incomplete
+            log::error!("Failed to export user data: {e:?}");
+            actix_web::Error::from(e)
+        }));
+    
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .insert_header(attachment("tsumego-export.tar"))
+        .streaming(files))
+}
+
+#[get("/api/export/stats.csv")]
+async fn export_stats_csv(state: State, user: User) -> Result<impl Responder> {
+    let mut conn = state.db.acquire().await?;
+    let stats = UserExport::get_stats(&mut conn, user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(attachment("tsumego-stats.csv"))
+        .body(stats_csv(&stats)))
+}
+
+#[get("/api/export/reviews.csv")]
+async fn export_reviews_csv(state: State, user: User) -> Result<impl Responder> {
+    let mut conn = state.db.acquire().await?;
+    let reviews = UserExport::get_reviews(&mut conn, user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv")
+        .insert_header(attachment("tsumego-reviews.csv"))
+        .body(reviews_csv(&reviews)))
+}
+
+/// `POST /api/import`: imports the `export.json` file from an archive
+/// previously exported from `/api/export`, possibly from another instance of
+/// this application.
+async fn import_json(state: State, user: User, export: Json<UserExport>) -> Result<impl Responder> {
+    if !export.is_valid() {
+        return Err(AppError::BAD_REQUEST);
+    }
+    
+    let outcome = export.import_for_user(&state, &user)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[derive(serde::Deserialize)]
+struct AnkiImport {
+    revlog: Vec<AnkiRevlogEntry>,
+}
+
+/// `POST /api/import/anki`: imports review history from Anki's review log,
+/// and rebuilds the user's stats for the affected tsumego.
+async fn import_anki(state: State, user: User, import: Json<AnkiImport>) -> Result<impl Responder> {
+    let (reviews, num_skipped) = ImportedReview::from_anki_revlog(import.into_inner().revlog)
+        .or_400_bad_request()?;
+    
+    let mut outcome = ImportedReview::import_for_user(&state, user.id, &reviews)
+        .await?;
+    outcome.num_skipped += num_skipped;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+/// `POST /api/import/csv`: imports review history from CSV with the columns
+/// `name,date,ease`, and rebuilds the user's stats for the affected tsumego.
+async fn import_csv(state: State, user: User, csv: String) -> Result<impl Responder> {
+    let reviews = ImportedReview::from_csv(&csv)
+        .or_400_bad_request()?;
+    
+    let outcome = ImportedReview::import_for_user(&state, user.id, &reviews)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(outcome))
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, middleware, test, App};
+    use serde_json::{json, Value};
+    
+    use crate::{
+        mailer::MemoryMailer,
+        model::UserExport,
+        state::{self, insert_test_user, State},
+    };
+    
+    /// Enough reviews that each import body is larger than actix-web's
+    /// default limit for it.
+    const NUM_REVIEWS: i64 = 40_000;
+    
+    #[actix_web::test]
+    async fn import_long_history() {
+        let mailer = MemoryMailer::default();
+        let state = state::for_test(Box::new(mailer.clone())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(authlogic::middleware::<State>))
+        ).await;
+        
+        // Log in by following a login link
+        authlogic::mail::issue_login_challenge(&state, &user)
+            .await
+            .unwrap();
+        crate::mail_queue::send_due(&state)
+            .await
+            .unwrap();
+        let link = mailer.last_link_to("alice@example.com")
+            .expect("Email should contain a link");
+        let path = link.strip_prefix(state.cfg.base_url.as_ref())
+            .expect("Link should be to this site");
+        let response = test::call_service(&app, test::TestRequest::get().uri(&format!("/{path}")).to_request())
+            .await;
+        let cookie = response.response()
+            .cookies()
+            .find(|c| c.name() == state.cfg.session_token_cookie_name)
+            .expect("Response should set the session cookie")
+            .into_owned();
+        
+        // None of these tsumego exist, so every review is skipped
+        let export = UserExport::get_for_user(&state, &user)
+            .await
+            .unwrap();
+        let mut export = serde_json::to_value(export).unwrap();
+        export["reviews"] = (0..NUM_REVIEWS)
+            .map(|i| json!({"tsumego": format!("No such problem {i}"), "reviewDate": "2024-01-30T09:15:00", "grade": 2, "usedHint": false}))
+            .collect();
+        let revlog: Vec<Value> = (0..NUM_REVIEWS)
+            .map(|i| json!({"tsumego": format!("No such problem {i}"), "id": 1706606100000 + i, "ease": 3}))
+            .collect();
+        let csv: String = (0..NUM_REVIEWS)
+            .map(|i| format!("No such problem {i},2024-01-30,3\n"))
+            .collect();
+        
+        let requests = [
+            test::TestRequest::post().uri("/api/import").set_json(&export),
+            test::TestRequest::post().uri("/api/import/anki").set_json(json!({"revlog": revlog})),
+            test::TestRequest::post().uri("/api/import/csv").set_payload(csv),
+        ];
+        for request in requests {
+            let request = request.cookie(cookie.clone()).to_request();
+            let response = test::call_service(&app, request)
+                .await;
+            assert_eq!(StatusCode::OK, response.status());
+            
+            let outcome: Value = test::read_body_json(response).await;
+            assert_eq!(Some(NUM_REVIEWS), outcome["numSkipped"].as_i64(), "{outcome}");
+        }
+    }
+}
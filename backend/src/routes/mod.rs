@@ -1,19 +1,38 @@
 use actix_web::{
+    http::header,
     web::ServiceConfig,
     FromRequest,
+    HttpRequest,
 };
 
+use crate::{
+    auth::{ApiScope, ApiToken},
+    model::User,
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+mod admin;
 mod auth;
+mod export;
 mod index;
+mod oidc;
+mod preferences;
 mod srs;
+mod tokens;
 mod tsumego;
 
 pub use auth::confirmation_link;
 
 /// Declares all routes for the application.
 pub fn declare_routes(conf: &mut ServiceConfig) {
+    admin::declare_routes(conf);
     auth::declare_routes(conf);
+    export::declare_routes(conf);
+    oidc::declare_routes(conf);
+    preferences::declare_routes(conf);
     srs::declare_routes(conf);
+    tokens::declare_routes(conf);
     tsumego::declare_routes(conf);
     
     // Must declare static files last, since this service returns 404 errors
@@ -22,12 +41,80 @@ pub fn declare_routes(conf: &mut ServiceConfig) {
     index::declare_routes(conf);
 }
 
-impl FromRequest for crate::model::User {
-    type Error = crate::result::AppError;
-    type Future = std::future::Ready<crate::result::Result<crate::model::User>>;
+/// The future type for extractors which need to query the database.
+type LocalFuture<T> = std::pin::Pin<Box<dyn std::future::Future<Output = Result<T>>>>;
+
+/// The routes which can be used with a personal API token instead of a
+/// session, and the scope which the token needs for each. Every other route
+/// rejects API tokens.
+const API_TOKEN_ROUTES: &[(&str, &str, ApiScope)] = &[
+    ("GET", "/api/stats", ApiScope::ReadStats),
+    ("GET", "/api/get_pending", ApiScope::ReadStats),
+    ("POST", "/api/review", ApiScope::SubmitReviews),
+    ("POST", "/api/problem/{id}/hint", ApiScope::SubmitReviews),
+];
+
+impl FromRequest for User {
+    type Error = AppError;
+    type Future = LocalFuture<User>;
 
-    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
-        let auth = authlogic::require_user_from_request::<crate::state::State>(req);
-        std::future::ready(auth)
+    fn from_request(req: &HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            if let Some(token) = bearer_token(&req) {
+                return authenticate_by_api_token(&req, &token).await;
+            }
+            
+            let user = authlogic::require_user_from_request::<State>(&req)?;
+            
+            // Users who still need to give a second factor, or must change
+            // their password, can only use routes which ask for `Auth` instead
+            // of `User`
+            if user.two_factor_pending {
+                Err(AppError::UNAUTHORIZED)
+            } else if user.require_password_change {
+                Err(authlogic::Error::RequirePasswordChange.into())
+            } else {
+                Ok(user)
+            }
+        })
     }
-}
\ No newline at end of file
+}
+
+/// Gets the token from an `Authorization: Bearer <token>` header, if there is
+/// one.
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    
+    value.strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+/// Authenticates a request by a personal API token, if the token is valid and
+/// has the scope needed for the requested route.
+async fn authenticate_by_api_token(req: &HttpRequest, token: &str) -> Result<User> {
+    let state = State::extract(req)
+        .await
+        .expect("State should be available from app data");
+    
+    let (user, scopes) = ApiToken::authenticate(&state, token)
+        .await?
+        .or_401_unauthorised()?;
+    
+    let method = req.method().as_str();
+    let pattern = req.match_pattern();
+    let allowed = API_TOKEN_ROUTES.iter()
+        .any(|&(m, p, scope)| m == method && Some(p) == pattern.as_deref() && scopes.contains(&scope));
+    
+    if !allowed {
+        log::info!("API token for user #{} can't be used for {method} {pattern:?}", user.id);
+        return Err(AppError::FORBIDDEN);
+    } else if user.require_password_change {
+        return Err(authlogic::Error::RequirePasswordChange.into());
+    }
+    
+    Ok(user)
+}
@@ -0,0 +1,103 @@
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    get,
+    web::{Query, ServiceConfig},
+    HttpRequest,
+    HttpResponse,
+    Responder,
+};
+
+use crate::{
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// The name of the cookie which holds the `state` parameter of a login in
+/// progress, so that the login can only be completed by the same browser.
+const STATE_COOKIE_NAME: &str = "oidc_state";
+
+/// Declares routes for logging in through an external OpenID Connect
+/// provider. These routes respond with "404 Not Found" if no provider is
+/// configured.
+pub fn declare_routes(conf: &mut ServiceConfig) {
+    conf.service(oidc_login)
+        .service(oidc_callback);
+}
+
+#[get("/api/oidc/login")]
+async fn oidc_login(state: State) -> Result<impl Responder> {
+    let provider = state.oidc_provider.as_ref()
+        .or_404_not_found()?;
+    
+    let login = crate::auth::begin_oidc_login(&state, provider)
+        .await?;
+    
+    // `SameSite=Lax` is needed so that the cookie is sent when the provider
+    // redirects the user back
+    let cookie = Cookie::build(STATE_COOKIE_NAME, login.state)
+        .path("/oidc/callback")
+        .http_only(true)
+        .secure(state.cfg.base_url.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .finish();
+    
+    let response = HttpResponse::Found()
+        .insert_header(("Location", login.redirect_url))
+        .cookie(cookie)
+        .finish();
+    Ok(response)
+}
+
+#[derive(serde::Deserialize)]
+struct CallbackQuery {
+    /// Not given if the login failed, in which case `error` is given instead.
+    code: Option<String>,
+    state: Option<String>,
+    /// Why the login failed, e.g. `access_denied` if the user cancelled it.
+    error: Option<String>,
+}
+
+/// The provider redirects the user here after they log in. This must match
+/// the URL from `crate::auth::oidc::redirect_uri`.
+#[get("/oidc/callback")]
+async fn oidc_callback(state: State, request: HttpRequest, query: Query<CallbackQuery>) -> Result<impl Responder> {
+    let provider = state.oidc_provider.as_ref()
+        .or_404_not_found()?;
+    let query = query.into_inner();
+    
+    let Some(code) = query.code else {
+        // The user is sent back to the login page, which is shown at the
+        // root when they aren't logged in
+        log::info!("OIDC login failed: {}", query.error.as_deref().unwrap_or("no code given"));
+        return Ok(redirect_to_root());
+    };
+    let login_state = query.state
+        .or_400_bad_request()?;
+    
+    let expected_state = request.cookie(STATE_COOKIE_NAME)
+        .or_401_unauthorised()?;
+    if expected_state.value() != login_state {
+        return Err(AppError::UNAUTHORIZED);
+    }
+    
+    let code = crate::auth::complete_oidc_login(&state, provider, &login_state, &code)
+        .await?;
+    authlogic::mail::complete_challenge(&state, code, &request)
+        .await?;
+    
+    Ok(redirect_to_root())
+}
+
+/// Redirects the user to the root page after a login attempt, and removes
+/// the cookie, since the attempt is finished.
+fn redirect_to_root() -> HttpResponse {
+    let mut removal = Cookie::build(STATE_COOKIE_NAME, "")
+        .path("/oidc/callback")
+        .finish();
+    removal.make_removal();
+    
+    HttpResponse::Found()
+        .insert_header(("Location", "/"))
+        .cookie(removal)
+        .finish()
+}
@@ -0,0 +1,86 @@
+use actix_web::{
+    get,
+    post,
+    web::{Json, Path, Query, ServiceConfig},
+    HttpResponse,
+    Responder,
+};
+
+use crate::{
+    auth::{verify_unsubscribe_token, MailingList},
+    model::{User, UserPreferences},
+    result::{OrAppError, Result},
+    state::State,
+};
+
+const UNSUBSCRIBE_PAGE: &str = include_str!("../../templates/unsubscribe.html");
+const UNSUBSCRIBED_PAGE: &str = include_str!("../../templates/unsubscribed.html");
+
+/// Declares routes for viewing and changing the user's preferences, and for
+/// unsubscribing from optional emails.
+pub fn declare_routes(conf: &mut ServiceConfig) {
+    conf.service(get_preferences)
+        .service(update_preferences)
+        .service(confirm_unsubscribe)
+        .service(unsubscribe);
+}
+
+#[get("/api/preferences")]
+async fn get_preferences(state: State, user: User) -> Result<impl Responder> {
+    let mut conn = state.db.acquire().await?;
+    let prefs = UserPreferences::get_for_user(&mut conn, user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(prefs))
+}
+
+#[post("/api/preferences")]
+async fn update_preferences(state: State, user: User, form: Json<UserPreferences>) -> Result<impl Responder> {
+    form.save(&state, user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(serde::Deserialize)]
+struct UnsubscribeQuery {
+    token: String,
+}
+
+/// Asks the user to confirm that they want to unsubscribe, from a link in an
+/// email. Following the link doesn't unsubscribe them straight away, since
+/// some mail scanners follow every link in an email.
+#[get("/unsubscribe/{list}")]
+async fn confirm_unsubscribe(state: State, list: Path<MailingList>, query: Query<UnsubscribeQuery>) -> Result<impl Responder> {
+    verify_unsubscribe_token(&state, list.into_inner(), &query.token)
+        .or_400_bad_request()?;
+    
+    Ok(html_page(UNSUBSCRIBE_PAGE))
+}
+
+/// Unsubscribes a user from a mailing list. This is requested by the form on
+/// the confirmation page, or directly by a mail client which supports
+/// one-click unsubscribing from the `List-Unsubscribe` header. The user
+/// doesn't need to be logged in, since the URL contains a signed token.
+#[post("/unsubscribe/{list}")]
+async fn unsubscribe(state: State, list: Path<MailingList>, query: Query<UnsubscribeQuery>) -> Result<impl Responder> {
+    let list = list.into_inner();
+    let user_id = verify_unsubscribe_token(&state, list, &query.token)
+        .or_400_bad_request()?;
+    
+    match list {
+        MailingList::Reminders => UserPreferences::disable_reminders(&state, user_id).await?,
+        MailingList::Digest => UserPreferences::disable_digest(&state, user_id).await?,
+    }
+    log::info!("User #{user_id} unsubscribed from {list:?}");
+    
+    Ok(html_page(UNSUBSCRIBED_PAGE))
+}
+
+fn html_page(body: &'static str) -> HttpResponse {
+    // Construct a response manually; using `actix_files` would send cache
+    // headers, which we don't want
+    HttpResponse::Ok()
+        .content_type("text/html")
+        .body(body)
+}
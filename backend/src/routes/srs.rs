@@ -1,19 +1,36 @@
 use actix_web::{
+    get,
     post,
     web::{Json, ServiceConfig},
     HttpResponse,
     Responder,
 };
+use serde_json::json;
 
 use crate::{
-    model::{Grade, User, UserTsumegoStats},
-    result::Result,
+    model::{Grade, ReviewDetails, User, UserDetails, UserTsumegoStats},
+    result::{AppError, Result},
     state::State,
 };
 
+/// The longest period over which a backlog of overdue reviews can be spread.
+const MAX_BACKLOG_RECOVERY_DAYS: i64 = 30;
+
 /// Declares routes for fetching Tsumego data.
 pub fn declare_routes(conf: &mut ServiceConfig) {
-    conf.service(post_review);
+    conf.service(get_stats)
+        .service(post_review)
+        .service(recover_backlog);
+}
+
+/// Gets the user's review counts for today. This is the same as `who_am_i`,
+/// but can also be used with a personal API token.
+#[get("/api/stats")]
+async fn get_stats(state: State, user: User) -> Result<impl Responder> {
+    let user_details = UserDetails::get_for_user(&state, user)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(user_details))
 }
 
 #[derive(serde::Deserialize)]
@@ -21,12 +38,38 @@ struct Review {
     #[serde(rename = "tsumegoID")]
     tsumego_id: i64,
     grade: Grade,
+    #[serde(flatten)]
+    details: ReviewDetails,
 }
 
 #[post("/api/review")]
 async fn post_review(state: State, user: User, review: Json<Review>) -> Result<impl Responder> {
-    let stats = UserTsumegoStats::update_on_review(&state, user.id, review.tsumego_id, review.grade)
+    if !review.details.is_valid() {
+        return Err(AppError::BAD_REQUEST);
+    }
+    
+    let stats = UserTsumegoStats::update_on_review(&state, user.id, review.tsumego_id, review.grade, &review.details)
         .await?;
     
     Ok(HttpResponse::Ok().json(stats))
 }
+
+#[derive(serde::Deserialize)]
+struct RecoverBacklogForm {
+    days: i64,
+}
+
+#[post("/api/recover_backlog")]
+async fn recover_backlog(state: State, user: User, form: Json<RecoverBacklogForm>) -> Result<impl Responder> {
+    let days = form.days;
+    if !(1..=MAX_BACKLOG_RECOVERY_DAYS).contains(&days) {
+        return Err(AppError::BAD_REQUEST);
+    }
+    
+    let num_deferred = UserTsumegoStats::spread_overdue_reviews(&state, user.id, days)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(json!({
+        "numDeferred": num_deferred,
+    })))
+}
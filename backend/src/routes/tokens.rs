@@ -0,0 +1,71 @@
+use actix_web::{
+    get,
+    post,
+    web::{Json, Path, ServiceConfig},
+    HttpResponse,
+    Responder,
+};
+
+use crate::{
+    auth::{ApiScope, ApiToken},
+    model::User,
+    result::{AppError, OrAppError, Result},
+    state::State,
+};
+
+/// Declares routes for managing personal API tokens. These routes can't be
+/// used with an API token, so a leaked token can't be used to create others.
+pub fn declare_routes(conf: &mut ServiceConfig) {
+    conf.service(list_tokens)
+        .service(create_token)
+        .service(revoke_token);
+}
+
+#[get("/api/tokens")]
+async fn list_tokens(state: State, user: User) -> Result<impl Responder> {
+    let mut conn = state.db.acquire().await?;
+    let tokens = ApiToken::get_all_for_user(&mut conn, user.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[derive(serde::Deserialize)]
+struct CreateTokenForm {
+    name: String,
+    scopes: Vec<ApiScope>,
+}
+
+#[derive(serde::Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    details: ApiToken,
+    /// The token itself, which is only shown this once.
+    token: String,
+}
+
+#[post("/api/tokens")]
+async fn create_token(state: State, user: User, form: Json<CreateTokenForm>) -> Result<impl Responder> {
+    let CreateTokenForm {name, mut scopes} = form.into_inner();
+    let name = name.trim();
+    if name.is_empty() || scopes.is_empty() {
+        return Err(AppError::BAD_REQUEST);
+    }
+    scopes.sort_unstable();
+    scopes.dedup();
+    
+    let (details, token) = ApiToken::create(&state, &user, name, scopes)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(CreatedToken {details, token}))
+}
+
+#[post("/api/tokens/{id}/revoke")]
+async fn revoke_token(state: State, user: User, id: Path<i64>) -> Result<impl Responder> {
+    ApiToken::revoke(&state, user.id, *id)
+        .await?
+        .then_some(())
+        .or_404_not_found()?;
+    
+    Ok(HttpResponse::Ok())
+}
@@ -1,13 +1,14 @@
 use actix_web::{
     get,
-    web::{Path, Query, ServiceConfig},
+    post,
+    web::{Json, Path, Query, ServiceConfig},
     HttpResponse,
     Responder,
 };
 use serde_json::json;
 
 use crate::{
-    model::{Tsumego, User},
+    model::{Hint, Tsumego, User},
     result::{AppError, OrAppError, Result},
     state::State,
 };
@@ -15,6 +16,7 @@ use crate::{
 /// Declares routes for fetching Tsumego data.
 pub fn declare_routes(conf: &mut ServiceConfig) {
     conf.service(get_tsumego)
+        .service(reveal_hint)
         .service(get_pending)
         .service(get_random_unstudied);
 }
@@ -28,6 +30,36 @@ async fn get_tsumego(state: State, id: Path<i64>) -> Result<impl Responder> {
     Ok(HttpResponse::Ok().json(tsumego))
 }
 
+#[derive(serde::Deserialize)]
+struct HintLevel {
+    level: i64,
+}
+
+/// Reveals a hint for a tsumego. This is a POST, since revealing a hint is
+/// recorded against the user's next review of the tsumego.
+#[post("/api/problem/{id}/hint")]
+async fn reveal_hint(state: State, user: User, id: Path<i64>, level: Json<HintLevel>) -> Result<impl Responder> {
+    let level = level.into_inner().level;
+    if !(1..=Hint::MAX_LEVEL).contains(&level) {
+        return Err(AppError::BAD_REQUEST);
+    }
+    
+    let tsumego = Tsumego::get_by_id(&state, *id)
+        .await?
+        .or_404_not_found()?;
+    
+    let hint = Hint::for_tsumego(&tsumego, level)
+        .or_404_not_found()?;
+    
+    Hint::record_reveal(&state, user.id, tsumego.id)
+        .await?;
+    
+    Ok(HttpResponse::Ok().json(json!({
+        "level": level,
+        "hint": hint,
+    })))
+}
+
 #[get("/api/get_pending")]
 async fn get_pending(state: State, user: User) -> Result<impl Responder> {
     let pending = Tsumego::get_pending(&state, user.id)
@@ -63,3 +95,61 @@ async fn get_random_unstudied(state: State, user: User, limit: Query<GetProblems
         "problems": problems,
     })))
 }
+
+#[cfg(test)]
+mod test {
+    use actix_web::{http::StatusCode, middleware, test, App};
+    use serde_json::{json, Value};
+    
+    use crate::{
+        auth::{ApiScope, ApiToken},
+        mailer::MemoryMailer,
+        state::{self, insert_test_user},
+    };
+    
+    #[actix_web::test]
+    async fn revealed_hint_caps_grade() {
+        let state = state::for_test(Box::new(MemoryMailer::default())).await;
+        let user = insert_test_user(&state, "alice@example.com").await;
+        sqlx::query!(r#"INSERT INTO tsumego (id, name, board, tree) VALUES (1, 'test', '', '{"A1": "win"}')"#)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let (_, token) = ApiToken::create(&state, &user, "test", vec![ApiScope::SubmitReviews])
+            .await
+            .unwrap();
+        
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .configure(crate::routes::declare_routes)
+                .wrap(middleware::from_fn(crate::middleware::csrf_middleware))
+        ).await;
+        let post = |uri: &str, body: Value| test::TestRequest::post()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .set_json(body)
+            .to_request();
+        
+        // Hints can't be revealed by a GET request, which isn't checked for
+        // cross-site requests
+        let request = test::TestRequest::get()
+            .uri("/api/problem/1/hint?level=2")
+            .insert_header(("Authorization", format!("Bearer {token}")))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(!response.status().is_success());
+        
+        let hint: Value = test::call_and_read_body_json(&app, post("/api/problem/1/hint", json!({"level": 2}))).await;
+        assert_eq!(json!({"level": 2, "hint": {"firstMove": "A1"}}), hint);
+        
+        let response = test::call_service(&app, post("/api/review", json!({"tsumegoID": 1, "grade": "Easy"}))).await;
+        assert_eq!(StatusCode::OK, response.status());
+        
+        let grade = sqlx::query_scalar!("SELECT grade FROM user_tsumego_reviews WHERE user_id = ?", user.id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(1, grade);
+    }
+}
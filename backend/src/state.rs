@@ -8,7 +8,17 @@ use sqlx::sqlite::{
     SqlitePoolOptions,
 };
 
-use crate::config::Config;
+use crate::{
+    auth::OidcProvider,
+    config::Config,
+    mail_templates::MailTemplates,
+    mailer::Mailer,
+    rate_limit::RateLimiter,
+};
+
+/// The signing key in the `.env` file in this repository, which must not be
+/// used in production.
+const INSECURE_SIGNING_KEY: &str = "insecure-development-signing-key";
 
 /// The `actix_web` application state, consisting of a database handle and the
 /// application config.
@@ -18,6 +28,18 @@ pub struct State(Arc<InnerState>);
 pub struct InnerState {
     pub db: SqlitePool,
     pub cfg: Config,
+    pub mailer: Box<dyn Mailer>,
+    pub mail_templates: MailTemplates,
+    pub rate_limiter: RateLimiter,
+    /// The external OpenID Connect provider which users can log in with, if
+    /// one is configured.
+    pub oidc_provider: Option<OidcProvider>,
+    /// A random identifier for this server process, which distinguishes it
+    /// from other processes sharing the same database.
+    pub instance_id: String,
+    /// The time which tests have set the clock to, if any; see `set_time`.
+    #[cfg(test)]
+    pub fixed_time: std::sync::Mutex<Option<crate::model::time::DateTime>>,
 }
 
 impl Deref for State {
@@ -45,6 +67,12 @@ impl FromRequest for State {
 pub async fn from_env() -> State {
     let cfg = Config::get_from_env();
     
+    // The development key is public, so anyone could sign tokens with it
+    if !cfg!(debug_assertions) && cfg.signing_key == INSECURE_SIGNING_KEY {
+        eprintln!("SIGNING_KEY must be changed from the development key in a release build");
+        std::process::exit(1);
+    }
+    
     let db = SqlitePoolOptions::new()
         .max_connections(cfg.database_pool_size)
         .connect(&cfg.database_url)
@@ -54,8 +82,124 @@ pub async fn from_env() -> State {
             std::process::exit(1);
         });
     
+    crate::migrations::prepare(&db, cfg.database_auto_migrate)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("Database schema is not usable: {err}");
+            std::process::exit(1);
+        });
+    
+    let mailer = crate::mailer::from_config(&cfg)
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to configure the mailer: {err:?}");
+            std::process::exit(1);
+        });
+    
+    mailer.check()
+        .unwrap_or_else(|err| {
+            eprintln!("Mail can't be delivered: {err:?}");
+            std::process::exit(1);
+        });
+    
+    let mail_templates = MailTemplates::load()
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to load email templates: {err:#}");
+            std::process::exit(1);
+        });
+    
+    let rate_limiter = RateLimiter::new(&cfg);
+    let oidc_provider = OidcProvider::from_config(&cfg);
+    
+    State(Arc::new(InnerState {
+        db,
+        cfg,
+        mailer,
+        mail_templates,
+        rate_limiter,
+        oidc_provider,
+        instance_id: new_instance_id(),
+        #[cfg(test)]
+        fixed_time: Default::default(),
+    }))
+}
+
+/// Builds application state for tests, using the config from `.env`, a new
+/// in-memory database, and the given mailer.
+#[cfg(test)]
+pub async fn for_test(mailer: Box<dyn Mailer>) -> State {
+    for_test_with_oidc(mailer, None).await
+}
+
+/// Builds application state for tests, as `for_test` does, with the given
+/// OpenID Connect provider.
+#[cfg(test)]
+pub async fn for_test_with_oidc(mailer: Box<dyn Mailer>, oidc_provider: Option<OidcProvider>) -> State {
+    let cfg = Config::for_test();
+    
+    // Each connection to an in-memory database gets a separate database, so
+    // the pool must only have one connection
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to open an in-memory database");
+    
+    crate::migrations::prepare(&db, true)
+        .await
+        .expect("Failed to run migrations");
+    
+    let mail_templates = MailTemplates::load()
+        .expect("Failed to load email templates");
+    let rate_limiter = RateLimiter::new(&cfg);
+    
     State(Arc::new(InnerState {
         db,
         cfg,
+        mailer,
+        mail_templates,
+        rate_limiter,
+        oidc_provider,
+        instance_id: new_instance_id(),
+        fixed_time: Default::default(),
     }))
 }
+
+#[cfg(test)]
+impl State {
+    /// Sets the time which `time_now` returns, so that tests can control time
+    /// sensitive behaviour such as two-factor codes.
+    pub fn set_time(&self, now: crate::model::time::DateTime) {
+        *self.fixed_time.lock().unwrap() = Some(now);
+    }
+}
+
+/// Inserts a verified user with no password, for tests.
+#[cfg(test)]
+pub async fn insert_test_user(state: &State, email: &str) -> crate::model::User {
+    use authlogic::{AppDb, PasswordHash, UserData, UserState};
+    
+    let mut user_data: UserData<State> = UserData {
+        user: crate::model::User {
+            id: -1,
+            email: email.to_string(),
+            display_name: "Alice".to_string(),
+            is_admin: false,
+            require_password_change: false,
+            two_factor_pending: false,
+        },
+        password_hash: PasswordHash::NONE,
+        state: UserState {
+            is_suspended: false,
+            require_email_verification: false,
+            require_password_change: false,
+        },
+    };
+    user_data.user.id = state.insert_user(&user_data)
+        .await
+        .expect("Failed to insert test user");
+    user_data.user
+}
+
+fn new_instance_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
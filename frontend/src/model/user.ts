@@ -3,6 +3,8 @@ interface UserDetails {
     readonly email: string;
     readonly displayName: string;
     readonly isAdmin: boolean;
+    readonly requirePasswordChange: boolean;
+    readonly twoFactorPending: boolean;
     reviewsDueToday: number;
     reviewsDoneToday: number;
 }